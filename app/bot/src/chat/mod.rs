@@ -0,0 +1,590 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
+use std::collections::HashMap;
+
+use crate::station::{search::SearchMode, MessageFormat};
+
+pub(crate) const CHATS_TABLE: &str = "Chats";
+/// Stations kept in a chat's `recent_stations`, most recent first
+pub(crate) const MAX_RECENT_STATIONS: usize = 5;
+
+/// Preferences of a chat, each one with its default until changed
+#[derive(Debug, PartialEq)]
+pub(crate) struct ChatSettings {
+    pub(crate) format: MessageFormat,
+    pub(crate) search_mode: SearchMode,
+    pub(crate) promo_opt_out: bool,
+    /// Opt-in threshold estimate, which costs a portal request per reply
+    pub(crate) trend_estimate: bool,
+    /// Most recent first, at most `MAX_RECENT_STATIONS`
+    pub(crate) recent_stations: Vec<String>,
+    /// `blocked_at` is set, see `mark_chat_blocked`
+    pub(crate) blocked: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self {
+            format: MessageFormat::Detailed,
+            search_mode: SearchMode::Fuzzy,
+            promo_opt_out: false,
+            trend_estimate: false,
+            recent_stations: Vec::new(),
+            blocked: false,
+        }
+    }
+}
+
+impl ChatSettings {
+    fn from_item(item: &HashMap<String, AttributeValue>) -> Self {
+        let defaults = Self::default();
+        Self {
+            format: item
+                .get("message_format")
+                .and_then(|format| format.as_s().ok())
+                .and_then(|format| MessageFormat::from_attr(format))
+                .unwrap_or(defaults.format),
+            search_mode: item
+                .get("search_mode")
+                .and_then(|mode| mode.as_s().ok())
+                .and_then(|mode| SearchMode::from_attr(mode))
+                .unwrap_or(defaults.search_mode),
+            promo_opt_out: item
+                .get("promo_opt_out")
+                .and_then(|opt_out| opt_out.as_bool().ok())
+                .copied()
+                .unwrap_or(defaults.promo_opt_out),
+            trend_estimate: item
+                .get("trend_estimate")
+                .and_then(|enabled| enabled.as_bool().ok())
+                .copied()
+                .unwrap_or(defaults.trend_estimate),
+            recent_stations: item
+                .get("recent_stations")
+                .and_then(|recent| recent.as_l().ok())
+                .map(|recent| {
+                    recent
+                        .iter()
+                        .filter_map(|name| name.as_s().ok().cloned())
+                        .collect()
+                })
+                .unwrap_or(defaults.recent_stations),
+            blocked: item.contains_key("blocked_at"),
+        }
+    }
+}
+
+/// Every setting of the chat with a single projected read, so a query costs
+/// one request on the chat record whatever it needs
+pub(crate) async fn get_chat_settings(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+) -> Result<ChatSettings> {
+    let result = client
+        .get_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .projection_expression(
+            "message_format, search_mode, promo_opt_out, trend_estimate, recent_stations, blocked_at",
+        )
+        .send()
+        .await?;
+
+    Ok(result
+        .item
+        .as_ref()
+        .map(ChatSettings::from_item)
+        .unwrap_or_default())
+}
+
+pub(crate) async fn set_chat_format(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    format: MessageFormat,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET message_format = :format")
+        .expression_attribute_values(":format", AttributeValue::S(format.as_attr().to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn set_search_mode(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    mode: SearchMode,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET search_mode = :mode")
+        .expression_attribute_values(":mode", AttributeValue::S(mode.as_attr().to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn set_promo_opt_out(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    opted_out: bool,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET promo_opt_out = :opt_out")
+        .expression_attribute_values(":opt_out", AttributeValue::Bool(opted_out))
+        .send()
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn set_trend_estimate(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    enabled: bool,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET trend_estimate = :enabled")
+        .expression_attribute_values(":enabled", AttributeValue::Bool(enabled))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Moves `station` to the front of `recent`, evicting the oldest entries
+/// beyond `cap`
+fn push_recent(mut recent: Vec<String>, station: &str, cap: usize) -> Vec<String> {
+    recent.retain(|name| name != station);
+    recent.insert(0, station.to_string());
+    recent.truncate(cap);
+    recent
+}
+
+fn string_list(names: &[String]) -> AttributeValue {
+    AttributeValue::L(
+        names
+            .iter()
+            .map(|name| AttributeValue::S(name.clone()))
+            .collect(),
+    )
+}
+
+/// Moves `station` to the front of the chat's recent stations, `previous`
+/// being the list read with the chat settings. A single conditional write:
+/// when another query of the same chat changed the list in the meantime this
+/// entry is dropped, which is acceptable for a convenience list
+pub(crate) async fn push_recent_station(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    previous: &[String],
+    station: &str,
+) -> Result<()> {
+    let recent = push_recent(previous.to_vec(), station, MAX_RECENT_STATIONS);
+    if recent == previous {
+        return Ok(());
+    }
+    let request = client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET recent_stations = :recent")
+        .expression_attribute_values(":recent", string_list(&recent));
+    let request = if previous.is_empty() {
+        request.condition_expression("attribute_not_exists(recent_stations)")
+    } else {
+        request
+            .condition_expression("recent_stations = :previous")
+            .expression_attribute_values(":previous", string_list(previous))
+    };
+
+    match request.send().await {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records a live refresh for the chat unless it already had one in the last
+/// `min_interval_ms`: the conditional write keeps the check atomic across
+/// concurrent invocations. `false` when rate limited.
+pub(crate) async fn try_start_refresh(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    now_ms: i64,
+    min_interval_ms: i64,
+) -> Result<bool> {
+    let result = client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET last_refresh_at = :now")
+        .condition_expression("attribute_not_exists(last_refresh_at) OR last_refresh_at <= :cutoff")
+        .expression_attribute_values(":now", AttributeValue::N(now_ms.to_string()))
+        .expression_attribute_values(
+            ":cutoff",
+            AttributeValue::N((now_ms - min_interval_ms).to_string()),
+        )
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Soft-deletes the chat after the bot was blocked or removed: the record is
+/// kept for statistics and `blocked_at` tells unsolicited sends, like alert
+/// fan-outs and broadcasts, to skip it. Replies are always sent: a message
+/// from the chat proves it can be written to again
+pub(crate) async fn mark_chat_blocked(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+    now_ms: i64,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("SET blocked_at = :now")
+        .expression_attribute_values(":now", AttributeValue::N(now_ms.to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Called when the chat talks to the bot again
+pub(crate) async fn clear_chat_blocked(
+    client: &DynamoDbClient,
+    table_name: &str,
+    chat_id: i64,
+) -> Result<()> {
+    client
+        .update_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::N(chat_id.to_string()))
+        .update_expression("REMOVE blocked_at")
+        .send()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mock_dynamodb_client, mock_dynamodb_client_with_status, request_json};
+
+    #[tokio::test]
+    async fn set_chat_format_stores_format_on_chat() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        set_chat_format(&client, CHATS_TABLE, 42, MessageFormat::Compact)
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["TableName"], CHATS_TABLE);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(
+            body["ExpressionAttributeValues"][":format"]["S"],
+            "compatto"
+        );
+    }
+
+    #[tokio::test]
+    async fn set_search_mode_stores_mode_on_chat() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        set_search_mode(&client, CHATS_TABLE, 42, SearchMode::Exact)
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(body["ExpressionAttributeValues"][":mode"]["S"], "esatta");
+    }
+
+    #[tokio::test]
+    async fn set_promo_opt_out_stores_flag_on_chat() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        set_promo_opt_out(&client, CHATS_TABLE, 42, true)
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(body["ExpressionAttributeValues"][":opt_out"]["BOOL"], true);
+    }
+
+    #[tokio::test]
+    async fn set_trend_estimate_stores_flag_on_chat() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        set_trend_estimate(&client, CHATS_TABLE, 42, true)
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(body["ExpressionAttributeValues"][":enabled"]["BOOL"], true);
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn get_chat_settings_reads_every_setting_at_once() {
+        let (client, request) = mock_dynamodb_client(
+            r#"{"Item":{
+                "message_format":{"S":"compatto"},
+                "search_mode":{"S":"esatta"},
+                "promo_opt_out":{"BOOL":true},
+                "trend_estimate":{"BOOL":true},
+                "recent_stations":{"L":[{"S":"Cesena"},{"S":"Lugo"}]},
+                "blocked_at":{"N":"1729454542656"}
+            }}"#,
+        );
+
+        assert_eq!(
+            get_chat_settings(&client, CHATS_TABLE, 42).await.unwrap(),
+            ChatSettings {
+                format: MessageFormat::Compact,
+                search_mode: SearchMode::Exact,
+                promo_opt_out: true,
+                trend_estimate: true,
+                recent_stations: names(&["Cesena", "Lugo"]),
+                blocked: true,
+            }
+        );
+        let body = request_json(request);
+        assert_eq!(body["TableName"], CHATS_TABLE);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(
+            body["ProjectionExpression"],
+            "message_format, search_mode, promo_opt_out, trend_estimate, recent_stations, blocked_at"
+        );
+    }
+
+    #[tokio::test]
+    async fn get_chat_settings_defaults_for_new_chats() {
+        let (client, _request) = mock_dynamodb_client("{}");
+
+        assert_eq!(
+            get_chat_settings(&client, CHATS_TABLE, 42).await.unwrap(),
+            ChatSettings::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn get_chat_settings_ignores_unknown_values() {
+        let (client, _request) = mock_dynamodb_client(
+            r#"{"Item":{"message_format":{"S":"breve"},"search_mode":{"S":"boh"}}}"#,
+        );
+
+        assert_eq!(
+            get_chat_settings(&client, CHATS_TABLE, 42).await.unwrap(),
+            ChatSettings::default()
+        );
+    }
+
+    #[test]
+    fn push_recent_adds_to_front() {
+        assert_eq!(
+            push_recent(names(&["Lugo"]), "Cesena", 5),
+            names(&["Cesena", "Lugo"])
+        );
+    }
+
+    #[test]
+    fn push_recent_evicts_oldest_beyond_cap() {
+        assert_eq!(
+            push_recent(names(&["E", "D", "C", "B", "A"]), "F", 5),
+            names(&["F", "E", "D", "C", "B"])
+        );
+    }
+
+    #[test]
+    fn push_recent_moves_repeated_station_to_front() {
+        assert_eq!(
+            push_recent(names(&["Lugo", "Cesena", "Borello"]), "Cesena", 5),
+            names(&["Cesena", "Lugo", "Borello"])
+        );
+    }
+
+    #[tokio::test]
+    async fn push_recent_station_writes_list_once() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        push_recent_station(
+            &client,
+            CHATS_TABLE,
+            42,
+            &names(&["Lugo", "Cesena"]),
+            "Cesena",
+        )
+        .await
+        .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["UpdateExpression"], "SET recent_stations = :recent");
+        assert_eq!(body["ConditionExpression"], "recent_stations = :previous");
+        assert_eq!(
+            body["ExpressionAttributeValues"][":recent"]["L"],
+            serde_json::json!([{"S": "Cesena"}, {"S": "Lugo"}])
+        );
+        assert_eq!(
+            body["ExpressionAttributeValues"][":previous"]["L"],
+            serde_json::json!([{"S": "Lugo"}, {"S": "Cesena"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn push_recent_station_creates_missing_list() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        push_recent_station(&client, CHATS_TABLE, 42, &[], "Cesena")
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(
+            body["ConditionExpression"],
+            "attribute_not_exists(recent_stations)"
+        );
+        assert_eq!(
+            body["ExpressionAttributeValues"][":recent"]["L"],
+            serde_json::json!([{"S": "Cesena"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn push_recent_station_skips_write_when_already_first() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        push_recent_station(
+            &client,
+            CHATS_TABLE,
+            42,
+            &names(&["Cesena", "Lugo"]),
+            "Cesena",
+        )
+        .await
+        .unwrap();
+
+        request.expect_no_request();
+    }
+
+    #[tokio::test]
+    async fn push_recent_station_drops_entry_on_concurrent_change() {
+        let (client, _request) = mock_dynamodb_client_with_status(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException","message":"The conditional request failed"}"#,
+        );
+
+        assert!(
+            push_recent_station(&client, CHATS_TABLE, 42, &names(&["Lugo"]), "Cesena")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn try_start_refresh_records_refresh_time() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        assert!(try_start_refresh(&client, CHATS_TABLE, 42, 120_000, 60_000)
+            .await
+            .unwrap());
+
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(body["ExpressionAttributeValues"][":now"]["N"], "120000");
+        assert_eq!(body["ExpressionAttributeValues"][":cutoff"]["N"], "60000");
+    }
+
+    #[tokio::test]
+    async fn try_start_refresh_is_rate_limited() {
+        let (client, _request) = mock_dynamodb_client_with_status(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException","message":"The conditional request failed"}"#,
+        );
+
+        assert!(
+            !try_start_refresh(&client, CHATS_TABLE, 42, 120_000, 60_000)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn try_start_refresh_propagates_other_errors() {
+        let (client, _request) = mock_dynamodb_client_with_status(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"Requested resource not found"}"#,
+        );
+
+        assert!(try_start_refresh(&client, CHATS_TABLE, 42, 120_000, 60_000)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn mark_chat_blocked_sets_marker() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        mark_chat_blocked(&client, CHATS_TABLE, 42, 1729454542656)
+            .await
+            .unwrap();
+
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "42");
+        assert_eq!(body["UpdateExpression"], "SET blocked_at = :now");
+        assert_eq!(
+            body["ExpressionAttributeValues"][":now"]["N"],
+            "1729454542656"
+        );
+    }
+
+    #[tokio::test]
+    async fn clear_chat_blocked_removes_marker() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        clear_chat_blocked(&client, CHATS_TABLE, 42).await.unwrap();
+
+        assert_eq!(
+            request_json(request)["UpdateExpression"],
+            "REMOVE blocked_at"
+        );
+    }
+}
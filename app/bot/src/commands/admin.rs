@@ -0,0 +1,182 @@
+use std::future::IntoFuture;
+use teloxide::{
+    payloads::SendMessageSetters,
+    prelude::{Bot, Requester},
+    types::{Message, ParseMode},
+    utils::command::BotCommands,
+};
+use tracing::error;
+
+use super::utils;
+use crate::station;
+
+/// Commands reserved to the maintainers listed in `ADMIN_USER_IDS`
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase")]
+pub(crate) enum AdminCommand {
+    /// Numero approssimativo di stazioni salvate
+    Dimensioni,
+    /// Stazioni con valori mancanti o non aggiornati (e.g. /diagnostica 2 per la seconda pagina)
+    Diagnostica(String),
+    /// Stazioni più vicine a una ricerca con la relativa distanza (e.g. /match Cesena)
+    Match(String),
+    /// Elimina il record di una stazione, ricreato al prossimo aggiornamento (e.g. /resetstazione Cesena conferma)
+    ResetStazione(String),
+}
+
+/// Trailing word that confirms `/resetstazione`
+const RESET_CONFIRMATION: &str = "conferma";
+
+/// Candidates listed by `/match`
+const MATCH_CANDIDATES: usize = 10;
+
+pub(crate) fn is_admin(msg: &Message) -> bool {
+    let admins = parse_admin_ids(&std::env::var("ADMIN_USER_IDS").unwrap_or_default());
+    msg.from
+        .as_ref()
+        .is_some_and(|user| admins.contains(&user.id.0))
+}
+
+/// Station name and whether the reset was confirmed, `None` without a name
+fn parse_reset_args(args: &str) -> Option<(String, bool)> {
+    let args = args.trim();
+    let (name, confirmed) = match args.rsplit_once(' ') {
+        Some((name, last)) if last.eq_ignore_ascii_case(RESET_CONFIRMATION) => (name.trim(), true),
+        _ => (args, false),
+    };
+    (!name.is_empty()).then(|| (name.to_string(), confirmed))
+}
+
+async fn reset_station(dynamodb_client: &aws_sdk_dynamodb::Client, args: &str) -> String {
+    let name = match parse_reset_args(args) {
+        Some((name, true)) => name,
+        Some((name, false)) => {
+            return format!(
+                "Il record di '{}' sarà eliminato e ricreato al prossimo aggiornamento.\nPer confermare scrivi /resetstazione {} {}",
+                name, name, RESET_CONFIRMATION
+            )
+        }
+        None => return "Indica la stazione da eliminare, e.g. /resetstazione Cesena".to_string(),
+    };
+    match station::search::delete_station_record(dynamodb_client, "Stazioni", &name).await {
+        Ok(true) => format!(
+            "Record di '{}' eliminato, sarà ricreato al prossimo aggiornamento",
+            name
+        ),
+        Ok(false) => format!("Nessun record trovato per '{}'", name),
+        Err(e) => {
+            error!(error = %e, "Error deleting station {}", name);
+            format!("Impossibile eliminare il record di '{}'", name)
+        }
+    }
+}
+
+fn parse_admin_ids(value: &str) -> Vec<u64> {
+    value
+        .split(',')
+        .filter_map(|id| id.trim().parse::<u64>().ok())
+        .collect()
+}
+
+pub(crate) async fn admin_commands_handler(
+    bot: Bot,
+    msg: Message,
+    cmd: AdminCommand,
+) -> Result<(), teloxide::RequestError> {
+    let dynamodb_client = utils::dynamodb_client().await;
+
+    let text = match cmd {
+        AdminCommand::Dimensioni => {
+            match station::search::describe_table_item_count(&dynamodb_client, "Stazioni").await {
+                Ok(count) => format!(
+                    "Stazioni: ~{} elementi (stima aggiornata da DynamoDB ogni 6 ore circa)",
+                    count
+                ),
+                Err(e) => {
+                    error!(error = %e, "Error describing table Stazioni");
+                    "Impossibile leggere le dimensioni della tabella Stazioni".to_string()
+                }
+            }
+        }
+        AdminCommand::Diagnostica(page) => {
+            let page = page.trim().parse::<usize>().unwrap_or(1);
+            match station::search::list_stations_with_values(&dynamodb_client, "Stazioni").await {
+                Ok(stations) => {
+                    let now_ms = chrono::Utc::now().timestamp_millis();
+                    station::format_diagnostics(
+                        &station::stations_with_issues(
+                            stations,
+                            now_ms,
+                            station::STALE_AFTER_HOURS,
+                        ),
+                        now_ms,
+                        station::STALE_AFTER_HOURS,
+                        page,
+                    )
+                }
+                Err(e) => {
+                    error!(error = %e, "Error listing stations with values");
+                    "Impossibile leggere i valori delle stazioni".to_string()
+                }
+            }
+        }
+        AdminCommand::Match(query) if query.trim().is_empty() => {
+            "Indica la ricerca da analizzare, e.g. /match Cesena".to_string()
+        }
+        AdminCommand::Match(query) => {
+            station::search::match_explanation(query.trim(), MATCH_CANDIDATES)
+        }
+        AdminCommand::ResetStazione(args) => reset_station(&dynamodb_client, &args).await,
+    };
+
+    let markdown = utils::markdown_message(&text);
+    let plain = utils::markdown_to_plain(&markdown);
+    utils::send_with_plain_fallback(
+        || {
+            bot.send_message(msg.chat.id, markdown.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .into_future()
+        },
+        || bot.send_message(msg.chat.id, plain.clone()).into_future(),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_admin_ids_skips_invalid_entries() {
+        assert_eq!(parse_admin_ids("42, 1337,abc,,"), vec![42, 1337]);
+        assert!(parse_admin_ids("").is_empty());
+    }
+
+    #[test]
+    fn parse_reset_args_requires_confirmation() {
+        assert_eq!(
+            parse_reset_args(" S. Carlo "),
+            Some(("S. Carlo".to_string(), false))
+        );
+        assert_eq!(
+            parse_reset_args("S. Carlo Conferma"),
+            Some(("S. Carlo".to_string(), true))
+        );
+        assert_eq!(parse_reset_args(""), None);
+        assert_eq!(
+            parse_reset_args("conferma"),
+            Some(("conferma".to_string(), false))
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_station_without_confirmation_does_not_delete() {
+        // Any request would hit the mock and be recorded
+        let (client, request) = crate::test_utils::mock_dynamodb_client("{}");
+
+        let text = reset_station(&client, "Cesena").await;
+
+        assert!(text.ends_with("/resetstazione Cesena conferma"));
+        request.expect_no_request();
+    }
+}
@@ -1,12 +1,18 @@
 use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::future::IntoFuture;
 use teloxide::{
     payloads::SendMessageSetters,
     prelude::{Bot, Requester},
-    types::{LinkPreviewOptions, Message, ParseMode},
+    types::{ChatId, InputFile, LinkPreviewOptions, Me, Message, ParseMode},
     utils::command::BotCommands,
 };
+use tracing::{error, info};
 
-use crate::station;
+use crate::{
+    chat, fetch_status,
+    station::{self, search::SearchMode, MessageFormat, Stazione},
+};
+pub(crate) mod admin;
 pub(crate) mod utils;
 
 #[derive(BotCommands, Clone)]
@@ -17,91 +23,1324 @@ pub(crate) enum BaseCommand {
     /// Ottieni informazioni riguardanti il bot
     Info,
     ///  Inizia ad interagire con il bot
-    Start,
+    Start(String),
     /// Visualizza la lista delle stazioni disponibili
     Stazioni,
+    /// Visualizza la fonte ufficiale dei dati
+    Fonte,
+    /// Ottieni un link da condividere per una stazione (e.g. /link Cesena)
+    Link(String),
+    /// Elenca le stazioni con un livello sopra il valore indicato (e.g. /soprasoglia 2.5)
+    SopraSoglia(String),
+    /// Scegli il formato dei messaggi: /formato compatto o /formato dettagliato
+    Formato(String),
+    /// Visualizza più stazioni in un unico messaggio (e.g. /multi Cesena, S. Carlo)
+    Multi(String),
+    /// Richiedi l'aggiunta di una stazione non presente (e.g. /richiedistazione Ponte Nuovo)
+    RichiediStazione(String),
+    /// Variazione del livello dall'ultima lettura (e.g. /variazione Cesena)
+    Variazione(String),
+    /// Esporta tutte le stazioni in formato CSV
+    EsportaStazioni,
+    /// Guida all'uso del bot con esempi
+    Guida,
+    /// Identificativi della chat e del topic correnti
+    ChatInfo,
+    /// Stazioni sopra la soglia gialla, dalla più critica
+    Allarmi,
+    /// Valore con tutti i decimali e orario esatto della lettura (e.g. /preciso Cesena)
+    Preciso(String),
+    /// Legge il valore aggiornato di una stazione direttamente dalla fonte (e.g. /aggiorna Cesena)
+    Aggiorna(String),
+    /// Data dell'ultimo aggiornamento riuscito dei dati
+    Aggiornamento,
+    /// Ultime stazioni consultate nella chat
+    Recenti,
+    /// Posizione della stazione sulla mappa (e.g. /mappa Cesena)
+    Mappa(String),
+    /// Età dell'ultima lettura di una stazione e dell'ultimo aggiornamento (e.g. /latenza Cesena)
+    Latenza(String),
+    /// Ricerca delle stazioni: /ricerca_modo esatta o /ricerca_modo fuzzy
+    #[command(rename = "ricerca_modo")]
+    RicercaModo(String),
+    /// Attiva o disattiva i messaggi promozionali nella chat (e.g. /promo off)
+    Promo(String),
+    /// Attiva o disattiva la stima del superamento delle soglie nel formato dettagliato (e.g. /stima on)
+    Stima(String),
+}
+
+const STATION_PAYLOAD_PREFIX: &str = "station_";
+const MAX_MULTI_STATIONS: usize = 10;
+const MAX_STATION_REQUEST_LEN: usize = 100;
+/// Live refreshes hit the regional API directly: one per chat per minute
+const REFRESH_MIN_INTERVAL_MS: i64 = 60_000;
+const REGION_PAYLOAD_PREFIX: &str = "region_";
+const REGION_KEY: &str = "emilia_romagna";
+/// Historical spellings of the region key still found in shared links
+const REGION_KEY_ALIASES: [&str; 4] = ["er", "rer", "emiliaromagna", "emilia"];
+
+/// Deep-link payload received with `/start <payload>`
+#[derive(Debug, PartialEq)]
+enum StartPayload {
+    Station(String),
+    Region(String),
+    Welcome,
 }
 
 pub(crate) async fn base_commands_handler(
     bot: Bot,
     msg: Message,
+    me: Me,
     cmd: BaseCommand,
 ) -> Result<(), teloxide::RequestError> {
     let text = match cmd {
         BaseCommand::Help => BaseCommand::descriptions().to_string(),
-        BaseCommand::Start => {
-            if msg.chat.is_group() || msg.chat.is_supergroup() {
-                format!("Ciao {}! Scrivete il nome di una stazione da monitorare (e.g. /Cesena o `/S. Carlo`) 
-                        o cercatene una con /stazioni",
-                        msg.chat.title().unwrap_or(""))
-            } else {
-                format!("Ciao @{}! Scrivi il nome di una stazione da monitorare (e.g. `Cesena` o `/S. Carlo`) \
-                        o cercane una con /stazioni",
-                        msg.chat.username().unwrap_or(msg.chat.first_name().unwrap_or("")))
+        BaseCommand::Start(payload) => return start(&bot, &msg, &payload).await,
+        BaseCommand::Stazioni => match station::StationsSort::from_env() {
+            station::StationsSort::Ordinamento => {
+                let dynamodb_client = utils::dynamodb_client().await;
+                match station::search::list_stations_ordered(&dynamodb_client, "Stazioni").await {
+                    Ok(stations) if !stations.is_empty() => stations.join("\n"),
+                    Ok(_) => station::stations().join("\n"),
+                    Err(e) => {
+                        error!(error = %e, "Error listing stations by ordinamento");
+                        station::stations().join("\n")
+                    }
+                }
+            }
+            station::StationsSort::Name => station::stations().join("\n"),
+        },
+        BaseCommand::Fonte => source_message(),
+        BaseCommand::Link(name) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            let settings = chat_settings(&dynamodb_client, &msg).await;
+            match station::search::resolve_station_name(name.trim(), settings.search_mode) {
+                Some(station) => format!(
+                    "Link per la stazione {}: {}",
+                    station,
+                    station_link(me.username(), &station)
+                ),
+                None => "Nessuna stazione trovata, usa /link seguito dal nome di una stazione (e.g. /link Cesena)".to_string(),
+            }
+        }
+        BaseCommand::SopraSoglia(value) => match utils::parse_value(&value) {
+            Some(threshold) => {
+                let dynamodb_client = utils::dynamodb_client().await;
+                match station::search::list_stations_with_values(&dynamodb_client, "Stazioni").await
+                {
+                    Ok(stations) => station::format_stations_above(
+                        &station::stations_above(stations, threshold),
+                        threshold,
+                    ),
+                    Err(e) => {
+                        error!(error = %e, "Error listing stations with values");
+                        "Impossibile leggere i valori delle stazioni, riprova più tardi".to_string()
+                    }
+                }
+            }
+            None => "Indica un valore numerico, ad esempio /soprasoglia 2.5".to_string(),
+        },
+        BaseCommand::Formato(format) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            formato(&dynamodb_client, &msg, &format).await
+        }
+        BaseCommand::RicercaModo(mode) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            ricerca_modo(&dynamodb_client, &msg, &mode).await
+        }
+        BaseCommand::Recenti => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            recenti(&dynamodb_client, &msg).await
+        }
+        BaseCommand::Promo(setting) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            promo(&dynamodb_client, &msg, &setting).await
+        }
+        BaseCommand::Stima(setting) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            stima(&dynamodb_client, &msg, &setting).await
+        }
+        BaseCommand::Multi(names) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            multi(&dynamodb_client, &msg, &names).await
+        }
+        BaseCommand::RichiediStazione(name) => match validate_station_request(&name) {
+            Ok(name) => {
+                record_station_request(&bot, &msg, &name).await;
+                format!(
+                    "Grazie! La richiesta per la stazione '{}' è stata inoltrata ai manutentori",
+                    name
+                )
+            }
+            Err(e) => e.to_string(),
+        },
+        BaseCommand::Aggiornamento => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            match fetch_status::get_fetch_status(
+                &dynamodb_client,
+                fetch_status::FETCH_STATUS_TABLE,
+                REGION_KEY,
+            )
+            .await
+            {
+                Ok(Some(status)) => status.message(),
+                Ok(None) => "Nessun aggiornamento dei dati registrato".to_string(),
+                Err(e) => {
+                    error!(error = %e, "Error reading fetch status");
+                    "Impossibile leggere lo stato degli aggiornamenti, riprova più tardi"
+                        .to_string()
+                }
+            }
+        }
+        BaseCommand::Latenza(name) if name.trim().is_empty() => {
+            "Indica il nome di una stazione, ad esempio /latenza Cesena".to_string()
+        }
+        BaseCommand::Latenza(name) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            latenza(&dynamodb_client, &msg, name).await
+        }
+        BaseCommand::Aggiorna(name) if name.trim().is_empty() => {
+            "Indica il nome di una stazione, ad esempio /aggiorna Cesena".to_string()
+        }
+        BaseCommand::Aggiorna(name) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            aggiorna(&dynamodb_client, &msg, name).await
+        }
+        BaseCommand::Preciso(name) if name.trim().is_empty() => {
+            "Indica il nome di una stazione, ad esempio /preciso Cesena".to_string()
+        }
+        BaseCommand::Preciso(name) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            preciso(&dynamodb_client, &msg, name).await
+        }
+        BaseCommand::Variazione(name) if name.trim().is_empty() => {
+            "Indica il nome di una stazione, ad esempio /variazione Cesena".to_string()
+        }
+        BaseCommand::Variazione(name) => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            let settings = chat_settings(&dynamodb_client, &msg).await;
+            match find_station(&dynamodb_client, &settings, name).await {
+                Ok(Some(item)) => item.variation_message(),
+                Err(_) | Ok(None) => not_found_message(),
+            }
+        }
+        BaseCommand::EsportaStazioni => return export_stations(&bot, &msg).await,
+        BaseCommand::Mappa(name) if name.trim().is_empty() => {
+            "Indica il nome di una stazione, ad esempio /mappa Cesena".to_string()
+        }
+        BaseCommand::Mappa(name) => return mappa(&bot, &msg, name).await,
+        BaseCommand::Allarmi => {
+            let dynamodb_client = utils::dynamodb_client().await;
+            match station::search::list_stations_with_values(&dynamodb_client, "Stazioni").await {
+                Ok(stations) => {
+                    station::format_stations_in_alarm(&station::stations_in_alarm(stations))
+                }
+                Err(e) => {
+                    error!(error = %e, "Error listing stations with values");
+                    "Impossibile leggere i valori delle stazioni, riprova più tardi".to_string()
+                }
             }
         }
-        BaseCommand::Stazioni => station::stations().join("\n"),
+        BaseCommand::Guida => guide_message(),
+        BaseCommand::ChatInfo => chat_info_message(&msg),
         BaseCommand::Info => {
-            let info = "Bot Telegram che permette di leggere i livello idrometrici dei fiumi dell'Emilia Romagna \
-                              I dati idrometrici sono ottenuti dalle API messe a disposizione da allertameteo.regione.emilia-romagna.it\n\n\
+            format!("Bot Telegram che permette di leggere i livello idrometrici dei fiumi dell'Emilia Romagna \
+                              I dati idrometrici sono ottenuti dalle API messe a disposizione da {}\n\n\
                               Il progetto è completamente open-source (https://github.com/notdodo/erfiume_bot).\n\
                               Per donazioni per mantenere il servizio attivo: buymeacoffee.com/d0d0\n\n\
-                              Inizia con /start o /stazioni";
-            info.to_string()
+                              Inizia con /start o /stazioni",
+                              station::SOURCE_URL)
+        }
+    };
+
+    send_markdown(&bot, &msg, utils::markdown_message(&text)).await
+}
+
+const UNSUPPORTED_REGION_MESSAGE: &str = "La regione richiesta non è ancora supportata: i dati disponibili sono quelli dell'Emilia-Romagna.";
+
+async fn start(bot: &Bot, msg: &Message, payload: &str) -> Result<(), teloxide::RequestError> {
+    let dynamodb_client = utils::dynamodb_client().await;
+    // Restarting the bot after blocking it undoes the soft delete
+    let settings = chat_settings(&dynamodb_client, msg).await;
+    let markdown = match parse_start_payload(payload) {
+        StartPayload::Station(name) => {
+            match find_station(&dynamodb_client, &settings, name).await {
+                Ok(Some(item)) => {
+                    let points = trend_series(&item, &settings).await;
+                    utils::markdown_message(&station_message(&item, &settings, &points))
+                }
+                Err(_) | Ok(None) => welcome_message(msg),
+            }
+        }
+        StartPayload::Region(region) if region != REGION_KEY => format!(
+            "{}\n\n{}",
+            welcome_message(msg),
+            utils::escape_markdown_v2(UNSUPPORTED_REGION_MESSAGE)
+        ),
+        StartPayload::Region(_) | StartPayload::Welcome => welcome_message(msg),
+    };
+    send_markdown(bot, msg, markdown).await
+}
+
+/// Sends text already formatted as MarkdownV2, without link previews
+async fn send_markdown(
+    bot: &Bot,
+    msg: &Message,
+    markdown: String,
+) -> Result<(), teloxide::RequestError> {
+    let link_preview_options = LinkPreviewOptions {
+        is_disabled: true,
+        url: None,
+        prefer_small_media: false,
+        prefer_large_media: false,
+        show_above_text: false,
+    };
+    let plain = utils::markdown_to_plain(&markdown);
+    utils::send_with_plain_fallback(
+        || {
+            bot.send_message(msg.chat.id, markdown.clone())
+                .link_preview_options(link_preview_options.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .into_future()
+        },
+        || {
+            bot.send_message(msg.chat.id, plain.clone())
+                .link_preview_options(link_preview_options.clone())
+                .into_future()
+        },
+    )
+    .await
+}
+
+/// Station message followed by its position, so it can be opened in a map
+async fn mappa(bot: &Bot, msg: &Message, name: String) -> Result<(), teloxide::RequestError> {
+    let dynamodb_client = utils::dynamodb_client().await;
+    let settings = chat_settings(&dynamodb_client, msg).await;
+    let item = match find_station(&dynamodb_client, &settings, name).await {
+        Ok(Some(item)) => item,
+        Err(_) | Ok(None) => {
+            return send_markdown(bot, msg, utils::markdown_message(&not_found_message())).await
+        }
+    };
+    let points = trend_series(&item, &settings).await;
+    let text = station_message(&item, &settings, &points);
+    match item.coordinates() {
+        Some((latitude, longitude)) => {
+            send_markdown(bot, msg, utils::markdown_message(&text)).await?;
+            utils::send_with_retry(|| {
+                bot.send_location(msg.chat.id, latitude, longitude)
+                    .into_future()
+            })
+            .await
+        }
+        None => {
+            let text = format!("{}\nPosizione della stazione non disponibile", text);
+            send_markdown(bot, msg, utils::markdown_message(&text)).await
+        }
+    }
+}
+
+async fn export_stations(bot: &Bot, msg: &Message) -> Result<(), teloxide::RequestError> {
+    let dynamodb_client = utils::dynamodb_client().await;
+    match station::search::list_stations_with_values(&dynamodb_client, "Stazioni").await {
+        Ok(mut stations) => {
+            stations.sort_by(|a, b| a.nomestaz.cmp(&b.nomestaz));
+            let csv = station::stations_to_csv(&stations).into_bytes();
+            utils::send_with_retry(|| {
+                bot.send_document(
+                    msg.chat.id,
+                    InputFile::memory(csv.clone())
+                        .file_name(format!("stazioni_{}.csv", REGION_KEY)),
+                )
+                .into_future()
+            })
+            .await
+        }
+        Err(e) => {
+            error!(error = %e, "Error listing stations with values");
+            utils::send_with_retry(|| {
+                bot.send_message(
+                    msg.chat.id,
+                    "Impossibile esportare le stazioni, riprova più tardi",
+                )
+                .into_future()
+            })
+            .await
+        }
+    }
+}
+
+async fn aggiorna(dynamodb_client: &DynamoDbClient, msg: &Message, name: String) -> String {
+    let settings = chat_settings(dynamodb_client, msg).await;
+    let item = match find_station(dynamodb_client, &settings, name).await {
+        Ok(Some(item)) => item,
+        Err(_) | Ok(None) => return not_found_message(),
+    };
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    match chat::try_start_refresh(
+        dynamodb_client,
+        chat::CHATS_TABLE,
+        msg.chat.id.0,
+        now_ms,
+        REFRESH_MIN_INTERVAL_MS,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            return "Puoi aggiornare una stazione al massimo una volta al minuto, riprova tra poco"
+                .to_string()
+        }
+        Err(e) => {
+            error!(error = %e, "Error checking refresh rate limit");
+            return "Impossibile aggiornare la stazione, riprova più tardi".to_string();
+        }
+    }
+    match station::series::fetch_series(&item).await {
+        Ok(Some(points)) => {
+            let trend_points: &[(i64, f64)] = if settings.trend_estimate {
+                &points
+            } else {
+                &[]
+            };
+            station::series::with_latest_reading(item, &points)
+                .create_station_message_with_series(settings.format, trend_points)
+        }
+        Ok(None) => format!(
+            "{}: {}, impossibile aggiornare dalla fonte",
+            item.nomestaz,
+            station::series::SERIES_UNAVAILABLE
+        ),
+        Err(e) => {
+            error!(error = %e, "Error fetching live station data");
+            "Impossibile leggere il valore aggiornato dalla fonte, riprova più tardi".to_string()
+        }
+    }
+}
+
+async fn formato(dynamodb_client: &DynamoDbClient, msg: &Message, format: &str) -> String {
+    if format.trim().is_empty() {
+        let current = chat_settings(dynamodb_client, msg).await.format;
+        return format!(
+            "Formato attuale: {}\nUsa /formato compatto o /formato dettagliato per cambiarlo",
+            current.as_attr()
+        );
+    }
+    match MessageFormat::from_attr(format) {
+        Some(format) => {
+            match chat::set_chat_format(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0, format)
+                .await
+            {
+                Ok(()) => format!("Formato impostato: {}", format.as_attr()),
+                Err(e) => {
+                    error!(error = %e, "Error storing chat message format");
+                    "Impossibile salvare il formato, riprova più tardi".to_string()
+                }
+            }
+        }
+        None => "Formato non valido, usa /formato compatto o /formato dettagliato".to_string(),
+    }
+}
+
+async fn ricerca_modo(dynamodb_client: &DynamoDbClient, msg: &Message, mode: &str) -> String {
+    if mode.trim().is_empty() {
+        let current = chat_settings(dynamodb_client, msg).await.search_mode;
+        return format!(
+            "Ricerca attuale: {}\nUsa /ricerca_modo esatta o /ricerca_modo fuzzy per cambiarla",
+            current.as_attr()
+        );
+    }
+    match SearchMode::from_attr(mode) {
+        Some(mode) => {
+            match chat::set_search_mode(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0, mode)
+                .await
+            {
+                Ok(()) => format!("Ricerca impostata: {}", mode.as_attr()),
+                Err(e) => {
+                    error!(error = %e, "Error storing chat search mode");
+                    "Impossibile salvare la modalità di ricerca, riprova più tardi".to_string()
+                }
+            }
+        }
+        None => "Modalità non valida, usa /ricerca_modo esatta o /ricerca_modo fuzzy".to_string(),
+    }
+}
+
+/// Settings of the chat, the defaults when they can't be read. The chat has
+/// just written to the bot, so a stale blocked marker is cleared
+async fn chat_settings(dynamodb_client: &DynamoDbClient, msg: &Message) -> chat::ChatSettings {
+    let settings = chat::get_chat_settings(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0)
+        .await
+        .unwrap_or_else(|e| {
+            error!(error = %e, "Error reading chat settings");
+            chat::ChatSettings::default()
+        });
+    if settings.blocked {
+        if let Err(e) =
+            chat::clear_chat_blocked(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0).await
+        {
+            error!(error = %e, "Error clearing chat blocked marker");
+        }
+    }
+    settings
+}
+
+/// Station lookup honouring the chat's search mode, used by every command
+/// taking a station name
+async fn find_station(
+    dynamodb_client: &DynamoDbClient,
+    settings: &chat::ChatSettings,
+    name: String,
+) -> anyhow::Result<Option<Stazione>> {
+    station::search::get_station_with_mode(dynamodb_client, name, "Stazioni", settings.search_mode)
+        .await
+}
+
+async fn preciso(dynamodb_client: &DynamoDbClient, msg: &Message, name: String) -> String {
+    let settings = chat_settings(dynamodb_client, msg).await;
+    match find_station(dynamodb_client, &settings, name).await {
+        Ok(Some(item)) => item.precise_message(),
+        Err(_) | Ok(None) => not_found_message(),
+    }
+}
+
+async fn latenza(dynamodb_client: &DynamoDbClient, msg: &Message, name: String) -> String {
+    let settings = chat_settings(dynamodb_client, msg).await;
+    let item = match find_station(dynamodb_client, &settings, name).await {
+        Ok(Some(item)) => item,
+        Err(_) | Ok(None) => return not_found_message(),
+    };
+    let latency = item.latency_message(chrono::Utc::now().timestamp_millis());
+    match fetch_status::get_fetch_status(
+        dynamodb_client,
+        fetch_status::FETCH_STATUS_TABLE,
+        REGION_KEY,
+    )
+    .await
+    {
+        Ok(Some(status)) => format!("{}\n{}", latency, status.message()),
+        Ok(None) => latency,
+        Err(e) => {
+            error!(error = %e, "Error reading fetch status");
+            latency
+        }
+    }
+}
+
+async fn stima(dynamodb_client: &DynamoDbClient, msg: &Message, setting: &str) -> String {
+    let enabled = match setting.trim().to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            return "Usa /stima on o /stima off per attivare o disattivare la stima del superamento delle soglie"
+                .to_string()
         }
     };
+    match chat::set_trend_estimate(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0, enabled)
+        .await
+    {
+        Ok(()) if enabled => "Stima del superamento delle soglie attivata: le risposte dettagliate potrebbero essere più lente".to_string(),
+        Ok(()) => "Stima del superamento delle soglie disattivata".to_string(),
+        Err(e) => {
+            error!(error = %e, "Error storing trend estimate setting");
+            "Impossibile salvare l'impostazione, riprova più tardi".to_string()
+        }
+    }
+}
 
-    bot.send_message(msg.chat.id, utils::escape_markdown_v2(&text))
-        .link_preview_options(LinkPreviewOptions {
-            is_disabled: true,
-            url: None,
-            prefer_small_media: false,
-            prefer_large_media: false,
-            show_above_text: false,
-        })
-        .parse_mode(ParseMode::MarkdownV2)
-        .await?;
+async fn promo(dynamodb_client: &DynamoDbClient, msg: &Message, setting: &str) -> String {
+    let opted_out = match setting.trim().to_lowercase().as_str() {
+        "on" => false,
+        "off" => true,
+        _ => {
+            return "Usa /promo on o /promo off per attivare o disattivare i messaggi promozionali"
+                .to_string()
+        }
+    };
+    match chat::set_promo_opt_out(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0, opted_out)
+        .await
+    {
+        Ok(()) if opted_out => "Messaggi promozionali disattivati".to_string(),
+        Ok(()) => "Messaggi promozionali attivati".to_string(),
+        Err(e) => {
+            error!(error = %e, "Error storing promo opt-out");
+            "Impossibile salvare l'impostazione, riprova più tardi".to_string()
+        }
+    }
+}
+
+async fn compact_station_lines(
+    dynamodb_client: &DynamoDbClient,
+    settings: &chat::ChatSettings,
+    names: Vec<String>,
+) -> Vec<String> {
+    let mut lines = Vec::with_capacity(names.len() + 1);
+    for name in names {
+        let line = match find_station(dynamodb_client, settings, name.clone()).await {
+            Ok(Some(item)) => item.create_station_message(MessageFormat::Compact),
+            Err(_) | Ok(None) => format!("{}: stazione non trovata", name),
+        };
+        lines.push(line);
+    }
+    lines
+}
+
+async fn recenti(dynamodb_client: &DynamoDbClient, msg: &Message) -> String {
+    match chat::get_chat_settings(dynamodb_client, chat::CHATS_TABLE, msg.chat.id.0).await {
+        Ok(settings) if settings.recent_stations.is_empty() => {
+            "Nessuna stazione consultata di recente in questa chat".to_string()
+        }
+        Ok(settings) => {
+            compact_station_lines(dynamodb_client, &settings, settings.recent_stations.clone())
+                .await
+                .join("\n")
+        }
+        Err(e) => {
+            error!(error = %e, "Error reading recent stations");
+            "Impossibile leggere le stazioni recenti, riprova più tardi".to_string()
+        }
+    }
+}
+
+async fn multi(dynamodb_client: &DynamoDbClient, msg: &Message, names: &str) -> String {
+    let (names, truncated) = parse_station_list(names);
+    if names.is_empty() {
+        return "Indica le stazioni separate da virgola, ad esempio /multi Cesena, S. Carlo"
+            .to_string();
+    }
+
+    let settings = chat_settings(dynamodb_client, msg).await;
+    let mut lines = compact_station_lines(dynamodb_client, &settings, names).await;
+    if truncated {
+        lines.push(format!(
+            "Puoi visualizzare al massimo {} stazioni alla volta",
+            MAX_MULTI_STATIONS
+        ));
+    }
+    lines.join("\n")
+}
 
-    Ok(())
+/// Splits a comma separated list of station names, keeping at most
+/// `MAX_MULTI_STATIONS`; the flag tells whether some names were dropped
+fn parse_station_list(text: &str) -> (Vec<String>, bool) {
+    let names: Vec<String> = text
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    let truncated = names.len() > MAX_MULTI_STATIONS;
+    (
+        names.into_iter().take(MAX_MULTI_STATIONS).collect(),
+        truncated,
+    )
+}
+
+fn validate_station_request(name: &str) -> Result<String, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Indica il nome della stazione, ad esempio /richiedistazione Ponte Nuovo");
+    }
+    if name.chars().count() > MAX_STATION_REQUEST_LEN {
+        return Err("Il nome della stazione è troppo lungo");
+    }
+    Ok(name.to_string())
+}
+
+/// Logs the request with a fixed `event` field so that requests can be
+/// queried from the logs, and forwards it to `MAINTAINER_CHAT_ID` when set
+async fn record_station_request(bot: &Bot, msg: &Message, name: &str) {
+    info!(
+        event = "station_request",
+        station = name,
+        region = REGION_KEY,
+        chat_id = msg.chat.id.0,
+        thread_id = utils::thread_id_i64(msg),
+        "Station requested"
+    );
+
+    let Some(maintainer_chat_id) = std::env::var("MAINTAINER_CHAT_ID")
+        .ok()
+        .and_then(|id| id.trim().parse::<i64>().ok())
+    else {
+        return;
+    };
+    let text = format!(
+        "Richiesta nuova stazione: {} (regione {}, chat {})",
+        name, REGION_KEY, msg.chat.id.0
+    );
+    let markdown = utils::markdown_message(&text);
+    let plain = utils::markdown_to_plain(&markdown);
+    if let Err(e) = utils::send_with_plain_fallback(
+        || {
+            bot.send_message(ChatId(maintainer_chat_id), markdown.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .into_future()
+        },
+        || {
+            bot.send_message(ChatId(maintainer_chat_id), plain.clone())
+                .into_future()
+        },
+    )
+    .await
+    {
+        error!(error = %e, "Error forwarding station request");
+    }
+}
+
+/// The threshold estimate is opt-in with /stima and only part of the detailed
+/// format, so that everyone else never waits on the portal
+fn wants_trend(settings: &chat::ChatSettings) -> bool {
+    settings.trend_estimate && settings.format == MessageFormat::Detailed
+}
+
+/// Recent readings for the threshold estimate, downloaded only when
+/// `wants_trend`; empty when the series can't be read
+async fn trend_series(item: &Stazione, settings: &chat::ChatSettings) -> Vec<(i64, f64)> {
+    if !wants_trend(settings) {
+        return Vec::new();
+    }
+    match station::series::fetch_series(item).await {
+        Ok(points) => points.unwrap_or_default(),
+        Err(e) => {
+            error!(error = %e, "Error reading station time series for the trend");
+            Vec::new()
+        }
+    }
+}
+
+/// Station message in the chat's format, with the threshold estimate fitted
+/// on `points` when `wants_trend`
+fn station_message(
+    item: &Stazione,
+    settings: &chat::ChatSettings,
+    points: &[(i64, f64)],
+) -> String {
+    if wants_trend(settings) {
+        item.create_station_message_with_series(settings.format, points)
+    } else {
+        item.create_station_message(settings.format)
+    }
+}
+
+/// Welcome text as MarkdownV2: the chat title or username is escaped, the
+/// examples are kept as code spans
+fn welcome_message(msg: &Message) -> String {
+    if msg.chat.is_group() || msg.chat.is_supergroup() || msg.chat.is_channel() {
+        utils::markdown_template(
+            "Ciao {}! Scrivete il nome di una stazione da monitorare (e.g. /Cesena o `/S. Carlo`) \
+            o cercatene una con /stazioni",
+            &[msg.chat.title().unwrap_or("")],
+        )
+    } else {
+        utils::markdown_template(
+            "Ciao @{}! Scrivi il nome di una stazione da monitorare (e.g. `Cesena` o `/S. Carlo`) \
+            o cercane una con /stazioni",
+            &[msg
+                .chat
+                .username()
+                .unwrap_or(msg.chat.first_name().unwrap_or(""))],
+        )
+    }
+}
+
+/// Maps a legacy alias to the current region key, other keys are unchanged
+fn canonical_region_key(region: &str) -> &str {
+    if REGION_KEY_ALIASES.contains(&region) {
+        REGION_KEY
+    } else {
+        region
+    }
+}
+
+fn parse_start_payload(payload: &str) -> StartPayload {
+    let payload = payload.trim();
+    if let Some(name) = payload.strip_prefix(STATION_PAYLOAD_PREFIX) {
+        let name = utils::decode_start_payload(name);
+        if !name.trim().is_empty() {
+            return StartPayload::Station(name.trim().to_string());
+        }
+    } else if let Some(region) = payload.strip_prefix(REGION_PAYLOAD_PREFIX) {
+        let region = utils::decode_start_payload(region)
+            .trim()
+            .to_lowercase()
+            .replace([' ', '-'], "_");
+        if !region.is_empty() {
+            return StartPayload::Region(canonical_region_key(&region).to_string());
+        }
+    }
+    StartPayload::Welcome
+}
+
+fn station_link(bot_username: &str, station: &str) -> String {
+    format!(
+        "https://t.me/{}?start={}{}",
+        bot_username,
+        STATION_PAYLOAD_PREFIX,
+        utils::encode_start_payload(station)
+    )
+}
+
+const MAINTENANCE_MESSAGE: &str =
+    "Manutenzione in corso, il bot tornerà disponibile a breve. Riprova più tardi.";
+
+pub(crate) fn maintenance_mode() -> bool {
+    utils::env_flag("MAINTENANCE_MODE")
+}
+
+pub(crate) async fn maintenance_handler(
+    bot: Bot,
+    msg: Message,
+) -> Result<(), teloxide::RequestError> {
+    // Channels receive every post, don't flood them while in maintenance
+    if msg.chat.is_channel() {
+        return Ok(());
+    }
+    let markdown = utils::markdown_message(MAINTENANCE_MESSAGE);
+    utils::send_with_plain_fallback(
+        || {
+            bot.send_message(msg.chat.id, markdown.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .into_future()
+        },
+        || {
+            bot.send_message(msg.chat.id, MAINTENANCE_MESSAGE)
+                .into_future()
+        },
+    )
+    .await
+}
+
+fn source_message() -> String {
+    format!(
+        "I dati idrometrici sono forniti dall'Agenzia regionale per la sicurezza territoriale \
+        e la protezione civile dell'Emilia-Romagna.\nPagina ufficiale: {}",
+        station::SOURCE_URL
+    )
+}
+
+fn chat_info_message(msg: &Message) -> String {
+    let chat_type = if msg.chat.is_private() {
+        "privata"
+    } else if msg.chat.is_supergroup() {
+        "supergruppo"
+    } else if msg.chat.is_group() {
+        "gruppo"
+    } else {
+        "canale"
+    };
+    format!(
+        "Chat id: {}\nThread id: {}\nTipo: {}",
+        msg.chat.id.0,
+        utils::thread_id_i64(msg).map_or_else(|| "nessuno".to_string(), |id| id.to_string()),
+        chat_type
+    )
+}
+
+fn guide_message() -> String {
+    "Guida all'uso di erfiume\n\n\
+    Livello di una stazione: scrivi il nome della stazione, ad esempio Cesena o S. Carlo\n\
+    /stazioni: elenco delle stazioni disponibili\n\
+    /multi Cesena, S. Carlo: più stazioni in un solo messaggio\n\
+    /recenti: ultime stazioni consultate nella chat\n\
+    /variazione Cesena: variazione del livello dall'ultima lettura\n\
+    /preciso Cesena: valore con tutti i decimali e orario esatto\n\
+    /aggiorna Cesena: valore letto ora dalla fonte\n\
+    /aggiornamento: data dell'ultimo aggiornamento dei dati\n\
+    /latenza Cesena: età dell'ultima lettura della stazione\n\
+    /soprasoglia 2.5: stazioni con livello sopra 2.5 metri\n\
+    /allarmi: stazioni sopra la soglia gialla, dalla più critica\n\
+    /formato compatto o /formato dettagliato: formato dei messaggi della chat\n\
+    /ricerca_modo esatta o /ricerca_modo fuzzy: ricerca per nome esatto o approssimato\n\
+    /promo off o /promo on: disattiva o riattiva i messaggi promozionali\n\
+    /stima on o /stima off: stima di quando verrà superata la prossima soglia\n\
+    /link Cesena: link da condividere per aprire il bot sulla stazione\n\
+    /mappa Cesena: posizione della stazione sulla mappa\n\
+    /esportastazioni: tutte le stazioni in un file CSV\n\
+    /richiedistazione Ponte Nuovo: chiedi l'aggiunta di una stazione mancante\n\
+    /fonte: fonte dei dati\n\
+    /info: informazioni sul progetto\n\
+    /start: messaggio di benvenuto\n\
+    /help: elenco sintetico dei comandi\n\
+    /chatinfo: identificativi della chat e del topic\n\
+    /guida: questa guida"
+        .to_string()
+}
+
+fn not_found_message() -> String {
+    format!(
+        "Nessuna stazione trovata con la parola di ricerca.\n\
+        Inserisci esattamente il nome che vedi dalla pagina {}\n\
+        Ad esempio 'Cesena', 'Lavino di Sopra' o 'S. Carlo'.\n\
+        Se non sai quale cercare prova con /stazioni",
+        station::SOURCE_URL
+    )
+}
+
+/// Splits a trailing horizon from a station query, e.g. `Cesena 6h`
+fn parse_horizon(text: &str) -> (&str, Option<i64>) {
+    let text = text.trim();
+    if let Some((name, suffix)) = text.rsplit_once(' ') {
+        let hours = suffix
+            .strip_suffix(['h', 'H'])
+            .and_then(|hours| hours.parse::<i64>().ok())
+            .filter(|hours| (1..=station::series::MAX_HORIZON_HOURS).contains(hours));
+        if let (Some(hours), false) = (hours, name.trim().is_empty()) {
+            return (name.trim(), Some(hours));
+        }
+    }
+    (text, None)
+}
+
+const DONATION_PROMO: &str = "Contribuisci al progetto per mantenerlo attivo e sviluppare nuove funzionalità tramite una donazione: https://buymeacoffee.com/d0d0";
+const GITHUB_PROMO: &str = "Esplora o contribuisci al progetto open-source per sviluppare nuove funzionalità: https://github.com/notdodo/erfiume_bot";
+
+#[derive(Debug, PartialEq)]
+enum Promo {
+    Donation,
+    GitHub,
+}
+
+/// Selects zero or one promo: the donation one about 1 time in 10,
+/// otherwise the GitHub one about 1 time in 50
+fn pick_promo(rng: &mut fastrand::Rng) -> Option<Promo> {
+    if rng.u8(0..10) == 8 {
+        Some(Promo::Donation)
+    } else if rng.u8(0..50) == 8 {
+        Some(Promo::GitHub)
+    } else {
+        None
+    }
+}
+
+/// Chats that opted out never draw a promo
+fn append_promo(text: String, rng: &mut fastrand::Rng, opted_out: bool) -> String {
+    if opted_out {
+        return text;
+    }
+    let promo = match pick_promo(rng) {
+        Some(Promo::Donation) => DONATION_PROMO,
+        Some(Promo::GitHub) => GITHUB_PROMO,
+        None => return text,
+    };
+    format!("{}\n\n{}", text, promo)
 }
 
 pub(crate) async fn message_handler(
     bot: &Bot,
     msg: &Message,
     dynamodb_client: DynamoDbClient,
-) -> Result<teloxide::prelude::Message, teloxide::RequestError> {
-    let text = msg.text().unwrap();
-    let text = match station::search::get_station(
+) -> Result<(), teloxide::RequestError> {
+    let (text, horizon) = parse_horizon(msg.text().unwrap());
+    // The only read of the chat record for the whole query
+    let settings = chat_settings(&dynamodb_client, msg).await;
+    let text = match find_station(&dynamodb_client, &settings, text.to_string()).await {
+        Ok(Some(item)) => {
+            if let Err(e) = chat::push_recent_station(
                 &dynamodb_client,
-                text.to_string(),
-                "Stazioni",
+                chat::CHATS_TABLE,
+                msg.chat.id.0,
+                &settings.recent_stations,
+                &item.nomestaz,
             )
             .await
             {
-                Ok(Some(item)) => {
-                    if item.nomestaz != text {
-                        format!("{}\nSe non è la stazione corretta prova ad affinare la ricerca.", item.create_station_message())
-                    }else {
-                        item.create_station_message().to_string()
+                error!(error = %e, "Error storing recent station");
+            }
+            let message = match horizon {
+                // One download of the series serves both the range and the estimate
+                Some(hours) => match station::series::fetch_series(&item).await {
+                    Ok(Some(points)) => format!(
+                        "{}\n{}",
+                        station_message(&item, &settings, &points),
+                        station::series::range_message(&item, &points, hours)
+                    ),
+                    Ok(None) => format!(
+                        "{}\nUltime {}h: {}",
+                        station_message(&item, &settings, &[]),
+                        hours,
+                        station::series::SERIES_UNAVAILABLE
+                    ),
+                    Err(e) => {
+                        error!(error = %e, "Error reading station time series");
+                        format!(
+                            "{}\nStorico delle ultime {}h non disponibile",
+                            station_message(&item, &settings, &[]),
+                            hours
+                        )
                     }
+                },
+                None => {
+                    let points = trend_series(&item, &settings).await;
+                    station_message(&item, &settings, &points)
                 }
-                Err(_) | Ok(None) => "Nessuna stazione trovata con la parola di ricerca.\nInserisci esattamente il nome che vedi dalla pagina https://allertameteo.regione.emilia-romagna.it/livello-idrometrico\nAd esempio 'Cesena', 'Lavino di Sopra' o 'S. Carlo'.\nSe non sai quale cercare prova con /stazioni".to_string()
             };
-    let mut message = text.clone();
-    if fastrand::choose_multiple(0..10, 1)[0] == 8 {
-        message = format!("{}\n\nContribuisci al progetto per mantenerlo attivo e sviluppare nuove funzionalità tramite una donazione: https://buymeacoffee.com/d0d0", text);
-    }
-    if fastrand::choose_multiple(0..50, 1)[0] == 8 {
-        message = format!("{}\n\nEsplora o contribuisci al progetto open-source per sviluppare nuove funzionalità: https://github.com/notdodo/erfiume_bot", text);
-    }
-    bot.send_message(msg.chat.id, utils::escape_markdown_v2(&message))
-        .link_preview_options(LinkPreviewOptions {
-            is_disabled: false,
-            url: None,
-            prefer_small_media: true,
-            prefer_large_media: false,
-            show_above_text: false,
-        })
-        .parse_mode(ParseMode::MarkdownV2)
-        .await
+            if item.nomestaz != text {
+                format!(
+                    "{}\nSe non è la stazione corretta prova ad affinare la ricerca.",
+                    message
+                )
+            } else {
+                message
+            }
+        }
+        // Channels receive every post: stay silent unless a station matches
+        Err(_) | Ok(None) if msg.chat.is_channel() => return Ok(()),
+        Err(_) | Ok(None) => not_found_message(),
+    };
+    let message = append_promo(text, &mut fastrand::Rng::new(), settings.promo_opt_out);
+    let markdown = utils::markdown_message(&message);
+    let plain = utils::markdown_to_plain(&markdown);
+    let link_preview_options = LinkPreviewOptions {
+        is_disabled: false,
+        url: None,
+        prefer_small_media: true,
+        prefer_large_media: false,
+        show_above_text: false,
+    };
+    utils::send_with_plain_fallback(
+        || {
+            bot.send_message(msg.chat.id, markdown.clone())
+                .link_preview_options(link_preview_options.clone())
+                .parse_mode(ParseMode::MarkdownV2)
+                .into_future()
+        },
+        || {
+            bot.send_message(msg.chat.id, plain.clone())
+                .link_preview_options(link_preview_options.clone())
+                .into_future()
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mock_dynamodb_client, request_json};
+
+    #[test]
+    fn source_message_contains_source_url() {
+        assert!(station::SOURCE_URL.starts_with("https://"));
+        assert!(source_message().contains(station::SOURCE_URL));
+    }
+
+    #[test]
+    fn not_found_message_contains_source_url() {
+        let message = not_found_message();
+        assert!(message.contains(station::SOURCE_URL));
+        assert!(message.starts_with("Nessuna stazione trovata"));
+    }
+
+    #[test]
+    fn parse_start_payload_station() {
+        assert_eq!(
+            parse_start_payload("station_S-2E_Carlo"),
+            StartPayload::Station("S. Carlo".to_string())
+        );
+        assert_eq!(
+            parse_start_payload("station_S.%20Carlo"),
+            StartPayload::Station("S. Carlo".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_start_payload_region() {
+        assert_eq!(
+            parse_start_payload("region_marche"),
+            StartPayload::Region("marche".to_string())
+        );
+        assert_eq!(
+            parse_start_payload("region_Emilia-Romagna"),
+            StartPayload::Region(REGION_KEY.to_string())
+        );
+    }
+
+    #[test]
+    fn parse_start_payload_resolves_region_aliases() {
+        for payload in ["region_ER", "region_rer", "region_EmiliaRomagna"] {
+            assert_eq!(
+                parse_start_payload(payload),
+                StartPayload::Region(REGION_KEY.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_region_key_keeps_unknown_keys() {
+        assert_eq!(canonical_region_key("marche"), "marche");
+        assert_eq!(canonical_region_key(REGION_KEY), REGION_KEY);
+    }
+
+    #[test]
+    fn parse_start_payload_empty_is_welcome() {
+        assert_eq!(parse_start_payload(""), StartPayload::Welcome);
+        assert_eq!(parse_start_payload("  "), StartPayload::Welcome);
+    }
+
+    #[test]
+    fn parse_start_payload_unknown_falls_back_to_welcome() {
+        assert_eq!(parse_start_payload("Cesena"), StartPayload::Welcome);
+        assert_eq!(parse_start_payload("station_"), StartPayload::Welcome);
+        assert_eq!(parse_start_payload("region___"), StartPayload::Welcome);
+    }
+
+    #[test]
+    fn station_link_encodes_station_name() {
+        assert_eq!(
+            station_link("erfiume_bot", "Ca' de Caroli"),
+            "https://t.me/erfiume_bot?start=station_Ca-27_de_Caroli"
+        );
+    }
+
+    #[test]
+    fn parse_station_list_splits_and_trims() {
+        assert_eq!(
+            parse_station_list(" Cesena, S. Carlo ,,Lugo"),
+            (
+                vec![
+                    "Cesena".to_string(),
+                    "S. Carlo".to_string(),
+                    "Lugo".to_string()
+                ],
+                false
+            )
+        );
+        assert_eq!(parse_station_list(" , "), (vec![], false));
+    }
+
+    #[test]
+    fn parse_station_list_caps_stations() {
+        let text = (0..MAX_MULTI_STATIONS + 3)
+            .map(|i| format!("Stazione {}", i))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (names, truncated) = parse_station_list(&text);
+        assert_eq!(names.len(), MAX_MULTI_STATIONS);
+        assert_eq!(names[0], "Stazione 0");
+        assert!(truncated);
+    }
+
+    #[test]
+    fn validate_station_request_rejects_empty_name() {
+        assert!(validate_station_request("").is_err());
+        assert!(validate_station_request("   ").is_err());
+    }
+
+    #[test]
+    fn validate_station_request_rejects_long_name() {
+        assert!(validate_station_request(&"a".repeat(MAX_STATION_REQUEST_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn validate_station_request_trims_name() {
+        assert_eq!(
+            validate_station_request("  Ponte Nuovo "),
+            Ok("Ponte Nuovo".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_horizon_splits_duration_suffix() {
+        assert_eq!(parse_horizon("Cesena 6h"), ("Cesena", Some(6)));
+        assert_eq!(parse_horizon(" S. Carlo 12H "), ("S. Carlo", Some(12)));
+    }
+
+    #[test]
+    fn parse_horizon_without_duration() {
+        assert_eq!(parse_horizon("Cesena"), ("Cesena", None));
+        assert_eq!(parse_horizon("S. Carlo"), ("S. Carlo", None));
+        assert_eq!(parse_horizon("6h"), ("6h", None));
+        assert_eq!(parse_horizon("Cesena 0h"), ("Cesena 0h", None));
+        assert_eq!(parse_horizon("Cesena 100h"), ("Cesena 100h", None));
+    }
+
+    #[test]
+    fn append_promo_appends_at_most_one_promo() {
+        for seed in 0..1000 {
+            let message = append_promo(
+                "Cesena".to_string(),
+                &mut fastrand::Rng::with_seed(seed),
+                false,
+            );
+            let promos = [DONATION_PROMO, GITHUB_PROMO]
+                .iter()
+                .filter(|promo| message.contains(*promo))
+                .count();
+            assert!(promos <= 1, "seed {} appended {} promos", seed, promos);
+            assert!(message.starts_with("Cesena"));
+        }
+    }
+
+    #[test]
+    fn append_promo_prefers_donation() {
+        for seed in 0..1000 {
+            let donation_drawn = fastrand::Rng::with_seed(seed).u8(0..10) == 8;
+            let message = append_promo(
+                "Cesena".to_string(),
+                &mut fastrand::Rng::with_seed(seed),
+                false,
+            );
+            assert_eq!(message.ends_with(DONATION_PROMO), donation_drawn);
+        }
+    }
+
+    #[test]
+    fn append_promo_with_both_old_draws_hitting_yields_one_promo() {
+        // The old handler drew both numbers and, when both hit, built the
+        // GitHub promo over the original text dropping the donation one
+        let seed = (0..100_000)
+            .find(|seed| {
+                let mut rng = fastrand::Rng::with_seed(*seed);
+                rng.u8(0..10) == 8 && rng.u8(0..50) == 8
+            })
+            .unwrap();
+
+        assert_eq!(
+            pick_promo(&mut fastrand::Rng::with_seed(seed)),
+            Some(Promo::Donation)
+        );
+        assert_eq!(
+            append_promo(
+                "Cesena".to_string(),
+                &mut fastrand::Rng::with_seed(seed),
+                false
+            ),
+            format!("Cesena\n\n{}", DONATION_PROMO)
+        );
+    }
+
+    #[test]
+    fn append_promo_skips_opted_out_chats() {
+        for seed in 0..1000 {
+            assert_eq!(
+                append_promo(
+                    "Cesena".to_string(),
+                    &mut fastrand::Rng::with_seed(seed),
+                    true
+                ),
+                "Cesena"
+            );
+        }
+    }
+
+    #[test]
+    fn chat_info_message_contains_chat_and_thread_ids() {
+        let msg: Message = serde_json::from_str(
+            r#"{
+                "message_id": 10,
+                "message_thread_id": 7,
+                "is_topic_message": true,
+                "date": 1729454542,
+                "chat": {"id": -1001, "type": "supergroup", "title": "Fiumi", "is_forum": true},
+                "from": {"id": 42, "is_bot": false, "first_name": "Ada"},
+                "text": "/chatinfo"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            chat_info_message(&msg),
+            "Chat id: -1001\nThread id: 7\nTipo: supergruppo"
+        );
+    }
+
+    #[test]
+    fn welcome_message_escapes_group_title() {
+        let msg: Message = serde_json::from_str(
+            r#"{
+                "message_id": 10,
+                "date": 1729454542,
+                "chat": {"id": -1001, "type": "supergroup", "title": "Fiumi_(E.R.)"},
+                "from": {"id": 42, "is_bot": false, "first_name": "Ada"},
+                "text": "/start"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            welcome_message(&msg),
+            "Ciao Fiumi\\_\\(E\\.R\\.\\)\\! Scrivete il nome di una stazione da monitorare \\(e\\.g\\. /Cesena o `/S\\. Carlo`\\) o cercatene una con /stazioni"
+        );
+    }
+
+    #[tokio::test]
+    async fn preciso_honours_exact_search_mode() {
+        let msg: Message = serde_json::from_str(
+            r#"{
+                "message_id": 10,
+                "date": 1729454542,
+                "chat": {"id": 42, "type": "private", "first_name": "Ada"},
+                "from": {"id": 42, "is_bot": false, "first_name": "Ada"},
+                "text": "/preciso ecsena"
+            }"#,
+        )
+        .unwrap();
+        let (client, request) = mock_dynamodb_client(r#"{"Item":{"search_mode":{"S":"esatta"}}}"#);
+
+        assert_eq!(
+            preciso(&client, &msg, "ecsena".to_string()).await,
+            not_found_message()
+        );
+        // The only request is the chat settings read: the typo never reaches Stazioni
+        let body = request_json(request);
+        assert_eq!(body["TableName"], chat::CHATS_TABLE);
+    }
+
+    #[tokio::test]
+    async fn formato_stores_channel_setting_without_sender() {
+        // Channel posts carry no `from`: settings are keyed by the chat alone
+        let msg: Message = serde_json::from_str(
+            r#"{
+                "message_id": 10,
+                "date": 1729454542,
+                "chat": {"id": -1001234567890, "type": "channel", "title": "Fiumi"},
+                "text": "/formato compatto"
+            }"#,
+        )
+        .unwrap();
+        let (client, request) = mock_dynamodb_client("{}");
+
+        assert_eq!(
+            formato(&client, &msg, "compatto").await,
+            "Formato impostato: compatto"
+        );
+        let body = request_json(request);
+        assert_eq!(body["Key"]["id"]["N"], "-1001234567890");
+        assert_eq!(
+            body["ExpressionAttributeValues"][":format"]["S"],
+            "compatto"
+        );
+    }
+
+    #[tokio::test]
+    async fn stima_rejects_unknown_setting() {
+        let msg: Message = serde_json::from_str(
+            r#"{
+                "message_id": 10,
+                "date": 1729454542,
+                "chat": {"id": 42, "type": "private", "first_name": "Ada"},
+                "from": {"id": 42, "is_bot": false, "first_name": "Ada"},
+                "text": "/stima forse"
+            }"#,
+        )
+        .unwrap();
+        let (client, request) = mock_dynamodb_client("{}");
+
+        assert!(stima(&client, &msg, "forse")
+            .await
+            .starts_with("Usa /stima on"));
+        request.expect_no_request();
+    }
+
+    #[test]
+    fn guide_message_mentions_every_command() {
+        let guide = guide_message();
+        for command in BaseCommand::bot_commands() {
+            assert!(
+                guide.contains(&command.command),
+                "{} missing from the guide",
+                command.command
+            );
+        }
+    }
 }
@@ -1,5 +1,17 @@
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use std::{borrow::Cow, future::Future};
+use teloxide::{types::Message, ApiError, RequestError};
+use tracing::{error, warn};
+
+/// Telegram rejects texts longer than 4096 UTF-16 code units
+const MAX_MESSAGE_LEN: usize = 4096;
+const TRUNCATION_MARK: &str = "…";
+
+/// Escapes `text` so that MarkdownV2 renders it verbatim
 pub(crate) fn escape_markdown_v2(text: &str) -> String {
     text.replace("\\", "\\\\")
+        .replace("`", "\\`")
         .replace("_", "\\_")
         .replace("*", "\\*")
         .replace("[", "\\[")
@@ -18,3 +30,550 @@ pub(crate) fn escape_markdown_v2(text: &str) -> String {
         .replace(".", "\\.")
         .replace("!", "\\!")
 }
+
+/// Encodes a station name as a Telegram deep-link payload: only `A-Z`, `a-z`,
+/// `0-9`, `_` and `-` are allowed, so spaces become `_` and every other byte is
+/// escaped as `-XX` (hex)
+pub(crate) fn encode_start_payload(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b' ' => "_".to_string(),
+            b if b.is_ascii_alphanumeric() => (b as char).to_string(),
+            b => format!("-{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decodes a payload built by `encode_start_payload`, URL-encoded (`%XX`)
+/// sequences are accepted as well. Invalid escapes are kept as they are.
+pub(crate) fn decode_start_payload(payload: &str) -> String {
+    let bytes = payload.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => decoded.push(b' '),
+            b'-' | b'%' => {
+                let escaped = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match escaped {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(bytes[i]),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Parses a user supplied level in Italian or English notation: `2,5`, `2.5`,
+/// `1.234,5` and `1,234.5`. A single separator is always the decimal one,
+/// levels in meters rarely reach the thousands.
+pub(crate) fn parse_value(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", text),
+    };
+    if digits.is_empty()
+        || !digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == ',')
+    {
+        return None;
+    }
+
+    let (integer, decimals) = match (digits.rfind(','), digits.rfind('.')) {
+        // Both separators: the last one is the decimal separator
+        (Some(comma), Some(dot)) => {
+            let decimal = comma.max(dot);
+            (&digits[..decimal], Some(&digits[decimal + 1..]))
+        }
+        (Some(index), None) | (None, Some(index)) => {
+            let separator = digits.as_bytes()[index] as char;
+            if digits.matches(separator).count() == 1 {
+                (&digits[..index], Some(&digits[index + 1..]))
+            } else {
+                (digits, None)
+            }
+        }
+        (None, None) => (digits, None),
+    };
+
+    let integer = strip_thousands_separators(integer)?;
+    let number = match decimals {
+        Some(decimals) if decimals.is_empty() || !decimals.chars().all(|c| c.is_ascii_digit()) => {
+            return None
+        }
+        Some(decimals) => format!("{}{}.{}", sign, integer, decimals),
+        None => format!("{}{}", sign, integer),
+    };
+    number.parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+/// `1.234.567` or `1,234` to plain digits, rejecting misplaced separators
+fn strip_thousands_separators(integer: &str) -> Option<String> {
+    let groups: Vec<&str> = integer.split(['.', ',']).collect();
+    let valid = match groups.as_slice() {
+        [single] => !single.is_empty(),
+        [first, rest @ ..] => {
+            (1..=3).contains(&first.len()) && rest.iter().all(|group| group.len() == 3)
+        }
+        [] => false,
+    };
+    // A thousands separator must be the same character throughout
+    let mixed = integer.contains('.') && integer.contains(',');
+    (valid && !mixed).then(|| groups.concat())
+}
+
+pub(crate) async fn dynamodb_client() -> DynamoDbClient {
+    let shared_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    DynamoDbClient::new(&shared_config)
+}
+
+/// Reads a boolean feature flag from the environment (e.g. `MAINTENANCE_MODE=1`)
+pub(crate) fn env_flag(name: &str) -> bool {
+    parse_flag(std::env::var(name).ok().as_deref())
+}
+
+fn parse_flag(value: Option<&str>) -> bool {
+    matches!(
+        value.map(|v| v.trim().to_lowercase()).as_deref(),
+        Some("1") | Some("true") | Some("yes") | Some("on")
+    )
+}
+
+/// MarkdownV2 text from a template where backticks stay inline code spans and
+/// each `{}` is filled with the matching value rendered verbatim, so names and
+/// titles never alter the formatting; placeholders must be outside code spans
+pub(crate) fn markdown_template(template: &str, values: &[&str]) -> String {
+    let escape_template = |part: &str| escape_markdown_v2(part).replace("\\`", "`");
+    let mut parts = template.split("{}");
+    let mut markdown = escape_template(parts.next().unwrap_or_default());
+    for (index, part) in parts.enumerate() {
+        markdown.push_str(&escape_markdown_v2(
+            values.get(index).copied().unwrap_or_default(),
+        ));
+        markdown.push_str(&escape_template(part));
+    }
+    markdown
+}
+
+/// Text ready to be sent with MarkdownV2: cut to Telegram's limit, then escaped
+pub(crate) fn markdown_message(text: &str) -> String {
+    escape_markdown_v2(&truncate_message(text))
+}
+
+fn truncate_message(text: &str) -> Cow<'_, str> {
+    let length = text.encode_utf16().count();
+    if length <= MAX_MESSAGE_LEN {
+        return Cow::Borrowed(text);
+    }
+    warn!(length, "Message too long, truncating");
+    let budget = MAX_MESSAGE_LEN - TRUNCATION_MARK.encode_utf16().count();
+    let mut used = 0;
+    let end = text
+        .char_indices()
+        .find(|(_, c)| {
+            used += c.len_utf16();
+            used > budget
+        })
+        .map_or(text.len(), |(index, _)| index);
+    Cow::Owned(format!("{}{}", &text[..end], TRUNCATION_MARK))
+}
+
+/// Forum topic of the message, hiding teloxide's nested `ThreadId(MessageId(i32))`
+pub(crate) fn thread_id_i64(msg: &Message) -> Option<i64> {
+    msg.thread_id.map(|id| i64::from(id.0 .0))
+}
+
+/// How a failed Telegram request should be handled
+#[derive(Debug, PartialEq)]
+pub(crate) enum RequestErrorKind {
+    /// Network or I/O blip: an immediate retry is likely to succeed
+    Transient,
+    /// Telegram rejected the MarkdownV2 entities of the text
+    BadMarkdown,
+    /// The bot can no longer write to the chat
+    Blocked,
+    /// Retrying the same request would fail again
+    Permanent,
+}
+
+pub(crate) fn classify_request_error(error: &RequestError) -> RequestErrorKind {
+    match error {
+        RequestError::Network(_) | RequestError::Io(_) => RequestErrorKind::Transient,
+        RequestError::Api(ApiError::CantParseEntities(_)) => RequestErrorKind::BadMarkdown,
+        RequestError::Api(
+            ApiError::BotBlocked
+            | ApiError::BotKicked
+            | ApiError::BotKickedFromSupergroup
+            | ApiError::UserDeactivated,
+        ) => RequestErrorKind::Blocked,
+        _ => RequestErrorKind::Permanent,
+    }
+}
+
+/// Runs `send`, retrying it once on a transient failure; other failures are
+/// returned so that blocked chats can be marked by the caller
+pub(crate) async fn send_with_retry<F, Fut, T>(mut send: F) -> Result<(), RequestError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RequestError>>,
+{
+    let error = match send().await {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    match classify_request_error(&error) {
+        RequestErrorKind::Transient => {
+            warn!(error = %error, "Transient error sending message, retrying once");
+            send().await.map(|_| ())
+        }
+        RequestErrorKind::BadMarkdown => {
+            error!(error = %error, "Telegram rejected the MarkdownV2 text");
+            Err(error)
+        }
+        RequestErrorKind::Blocked | RequestErrorKind::Permanent => Err(error),
+    }
+}
+
+/// Sends MarkdownV2 with `send_markdown`; if Telegram cannot parse its
+/// entities, sends the same message once more as plain text with `send_plain`
+pub(crate) async fn send_with_plain_fallback<M, MFut, P, PFut, T, U>(
+    send_markdown: M,
+    send_plain: P,
+) -> Result<(), RequestError>
+where
+    M: FnMut() -> MFut,
+    MFut: Future<Output = Result<T, RequestError>>,
+    P: FnMut() -> PFut,
+    PFut: Future<Output = Result<U, RequestError>>,
+{
+    match send_with_retry(send_markdown).await {
+        Err(e) if classify_request_error(&e) == RequestErrorKind::BadMarkdown => {
+            warn!("Sending the message again as plain text");
+            send_with_retry(send_plain).await
+        }
+        result => result,
+    }
+}
+
+/// Recovers the text of a MarkdownV2 message built by this module: escapes
+/// are removed and code spans lose their backticks
+pub(crate) fn markdown_to_plain(markdown: &str) -> String {
+    let mut plain = String::with_capacity(markdown.len());
+    let mut chars = markdown.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => plain.extend(chars.next()),
+            '`' => {}
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flag_accepts_enabled_values() {
+        assert!(parse_flag(Some("1")));
+        assert!(parse_flag(Some("true")));
+        assert!(parse_flag(Some(" TRUE ")));
+    }
+
+    #[test]
+    fn parse_flag_rejects_disabled_or_missing_values() {
+        assert!(!parse_flag(Some("0")));
+        assert!(!parse_flag(Some("")));
+        assert!(!parse_flag(None));
+    }
+
+    #[test]
+    fn encode_start_payload_uses_allowed_characters() {
+        assert_eq!(encode_start_payload("S. Carlo"), "S-2E_Carlo");
+        assert_eq!(encode_start_payload("Forli'"), "Forli-27");
+        assert!(encode_start_payload("Ca' de Caroli")
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'));
+    }
+
+    #[test]
+    fn decode_start_payload_roundtrips() {
+        for name in [
+            "Cesena",
+            "S. Carlo",
+            "Ca' de Caroli",
+            "Forli'",
+            "Parma S. Siro",
+        ] {
+            assert_eq!(decode_start_payload(&encode_start_payload(name)), name);
+        }
+    }
+
+    #[test]
+    fn decode_start_payload_accepts_url_encoding() {
+        assert_eq!(decode_start_payload("S.%20Carlo"), "S. Carlo");
+    }
+
+    #[test]
+    fn decode_start_payload_keeps_invalid_escapes() {
+        assert_eq!(decode_start_payload("Ponte-Vico-"), "Ponte-Vico-");
+    }
+
+    #[test]
+    fn parse_value_accepts_dot_and_comma() {
+        assert_eq!(parse_value("2.5"), Some(2.5));
+        assert_eq!(parse_value(" 2,5 "), Some(2.5));
+        assert_eq!(parse_value("3"), Some(3.0));
+    }
+
+    #[test]
+    fn parse_value_rejects_invalid_input() {
+        assert_eq!(parse_value(""), None);
+        assert_eq!(parse_value("alto"), None);
+        assert_eq!(parse_value("NaN"), None);
+    }
+
+    #[test]
+    fn parse_value_accepts_thousands_separators() {
+        assert_eq!(parse_value("1.234,5"), Some(1234.5));
+        assert_eq!(parse_value("1,234.5"), Some(1234.5));
+        assert_eq!(parse_value("1.234.567"), Some(1234567.0));
+        assert_eq!(parse_value("1.234.567,25"), Some(1234567.25));
+    }
+
+    #[test]
+    fn parse_value_single_separator_is_decimal() {
+        assert_eq!(parse_value("1234.5"), Some(1234.5));
+        assert_eq!(parse_value("1234,5"), Some(1234.5));
+        assert_eq!(parse_value("-0,75"), Some(-0.75));
+    }
+
+    #[test]
+    fn parse_value_rejects_malformed_numbers() {
+        assert_eq!(parse_value("1.23,4"), None);
+        assert_eq!(parse_value("12.34.5"), None);
+        assert_eq!(parse_value("1,2"), Some(1.2));
+        assert_eq!(parse_value("2,"), None);
+        assert_eq!(parse_value(",5"), None);
+        assert_eq!(parse_value("1.234,5,6"), None);
+        assert_eq!(parse_value("1e3"), None);
+        assert_eq!(parse_value("-"), None);
+    }
+
+    fn supergroup_message(thread_id: Option<i32>) -> Message {
+        let mut message = serde_json::json!({
+            "message_id": 10,
+            "date": 1729454542,
+            "chat": {"id": -1001, "type": "supergroup", "title": "Fiumi", "is_forum": true},
+            "from": {"id": 42, "is_bot": false, "first_name": "Ada"},
+            "text": "Cesena"
+        });
+        if let Some(thread_id) = thread_id {
+            message["message_thread_id"] = thread_id.into();
+            message["is_topic_message"] = true.into();
+        }
+        serde_json::from_str(&message.to_string()).unwrap()
+    }
+
+    #[test]
+    fn thread_id_i64_reads_topic_messages() {
+        assert_eq!(thread_id_i64(&supergroup_message(Some(7))), Some(7));
+    }
+
+    #[test]
+    fn thread_id_i64_without_thread() {
+        assert_eq!(thread_id_i64(&supergroup_message(None)), None);
+    }
+
+    #[test]
+    fn escape_markdown_v2_escapes_station_names() {
+        assert_eq!(
+            escape_markdown_v2("S. Carlo (FC)_nuovo"),
+            "S\\. Carlo \\(FC\\)\\_nuovo"
+        );
+        assert_eq!(escape_markdown_v2("`Ponte`"), "\\`Ponte\\`");
+    }
+
+    #[test]
+    fn markdown_template_keeps_code_spans_and_escapes_values() {
+        assert_eq!(
+            markdown_template("Ciao {}! Prova `/S. Carlo`", &["Fiumi_(E.R.) `x`"]),
+            "Ciao Fiumi\\_\\(E\\.R\\.\\) \\`x\\`\\! Prova `/S\\. Carlo`"
+        );
+    }
+
+    #[test]
+    fn markdown_template_without_values() {
+        assert_eq!(markdown_template("Ciao {}!", &[]), "Ciao \\!");
+    }
+
+    #[test]
+    fn truncate_message_keeps_short_messages() {
+        let text = "a".repeat(MAX_MESSAGE_LEN);
+        assert!(matches!(truncate_message(&text), Cow::Borrowed(t) if t == text));
+    }
+
+    #[test]
+    fn truncate_message_cuts_at_the_limit() {
+        let truncated = truncate_message(&"a".repeat(MAX_MESSAGE_LEN + 1)).into_owned();
+        assert_eq!(truncated.encode_utf16().count(), MAX_MESSAGE_LEN);
+        assert!(truncated.ends_with(TRUNCATION_MARK));
+    }
+
+    #[test]
+    fn truncate_message_counts_utf16_units() {
+        // Emojis take two UTF-16 code units each
+        let truncated = truncate_message(&"🟠".repeat(MAX_MESSAGE_LEN)).into_owned();
+        assert!(truncated.encode_utf16().count() <= MAX_MESSAGE_LEN);
+        assert!(truncated.ends_with(TRUNCATION_MARK));
+    }
+
+    fn io_error() -> RequestError {
+        RequestError::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset",
+        ))
+    }
+
+    #[test]
+    fn classify_request_error_kinds() {
+        let network = reqwest::Client::new().get("not a url").build().unwrap_err();
+        assert_eq!(
+            classify_request_error(&RequestError::Network(network)),
+            RequestErrorKind::Transient
+        );
+        assert_eq!(
+            classify_request_error(&io_error()),
+            RequestErrorKind::Transient
+        );
+        assert_eq!(
+            classify_request_error(&RequestError::Api(ApiError::CantParseEntities(
+                "Bad Request: can't parse entities".to_string()
+            ))),
+            RequestErrorKind::BadMarkdown
+        );
+        assert_eq!(
+            classify_request_error(&RequestError::Api(ApiError::BotBlocked)),
+            RequestErrorKind::Blocked
+        );
+        assert_eq!(
+            classify_request_error(&RequestError::Api(ApiError::MessageTextIsEmpty)),
+            RequestErrorKind::Permanent
+        );
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_retries_transient_errors_once() {
+        let mut attempts = 0;
+        let result = send_with_retry(|| {
+            attempts += 1;
+            let outcome = if attempts == 1 {
+                Err(io_error())
+            } else {
+                Ok(())
+            };
+            async move { outcome }
+        })
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_gives_up_after_second_transient_error() {
+        let mut attempts = 0;
+        let result = send_with_retry(|| {
+            attempts += 1;
+            async { Err::<(), _>(io_error()) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_does_not_retry_blocked_chats() {
+        let mut attempts = 0;
+        let result = send_with_retry(|| {
+            attempts += 1;
+            async { Err::<(), _>(RequestError::Api(ApiError::BotBlocked)) }
+        })
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(RequestError::Api(ApiError::BotBlocked))
+        ));
+        assert_eq!(attempts, 1);
+    }
+
+    fn parse_error() -> RequestError {
+        RequestError::Api(ApiError::CantParseEntities(
+            "Bad Request: can't parse entities: Character '.' is reserved".to_string(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn send_with_plain_fallback_retries_as_plain_text_on_parse_error() {
+        let mut markdown_attempts = 0;
+        let mut plain_attempts = 0;
+        let result = send_with_plain_fallback(
+            || {
+                markdown_attempts += 1;
+                async { Err::<(), _>(parse_error()) }
+            },
+            || {
+                plain_attempts += 1;
+                async { Ok(()) }
+            },
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!((markdown_attempts, plain_attempts), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn send_with_plain_fallback_skips_plain_text_on_other_errors() {
+        let mut plain_attempts = 0;
+        let result = send_with_plain_fallback(
+            || async { Err::<(), _>(RequestError::Api(ApiError::BotBlocked)) },
+            || {
+                plain_attempts += 1;
+                async { Ok(()) }
+            },
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(plain_attempts, 0);
+    }
+
+    #[test]
+    fn markdown_to_plain_reverts_escaping() {
+        let text = "Valore: 2.2m (soglia_1) [a*b] `c` \\ fine!";
+        assert_eq!(markdown_to_plain(&markdown_message(text)), text);
+    }
+
+    #[test]
+    fn markdown_to_plain_drops_code_spans() {
+        assert_eq!(
+            markdown_to_plain(&markdown_template("Ciao {}! Scrivi `Cesena`", &["d0d0"])),
+            "Ciao d0d0! Scrivi Cesena"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
+
+use crate::local_time::format_rome;
+
+/// Written by the fetcher at the end of every run that read the region listing
+pub(crate) const FETCH_STATUS_TABLE: &str = "FetchStatus";
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct FetchStatus {
+    pub last_fetch_success: i64,
+    pub stations_updated: i64,
+}
+
+impl FetchStatus {
+    pub fn message(&self) -> String {
+        match format_rome(self.last_fetch_success) {
+            Some(datetime) => format!(
+                "Ultimo aggiornamento dei dati: {} ({} stazioni aggiornate)",
+                datetime, self.stations_updated
+            ),
+            None => "Data dell'ultimo aggiornamento non valida".to_string(),
+        }
+    }
+}
+
+pub(crate) async fn get_fetch_status(
+    client: &DynamoDbClient,
+    table_name: &str,
+    region: &str,
+) -> Result<Option<FetchStatus>> {
+    let result = client
+        .get_item()
+        .table_name(table_name)
+        .key("region", AttributeValue::S(region.to_string()))
+        .send()
+        .await?;
+
+    let number = |item: &std::collections::HashMap<String, AttributeValue>, field: &str| {
+        item.get(field)
+            .and_then(|value| value.as_n().ok())
+            .and_then(|value| value.parse::<i64>().ok())
+    };
+    Ok(result.item.and_then(|item| {
+        Some(FetchStatus {
+            last_fetch_success: number(&item, "last_fetch_success")?,
+            stations_updated: number(&item, "stations_updated").unwrap_or_default(),
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{mock_dynamodb_client, request_json};
+
+    #[tokio::test]
+    async fn get_fetch_status_reads_region_item() {
+        let (client, request) = mock_dynamodb_client(
+            r#"{"Item":{"region":{"S":"emilia_romagna"},"last_fetch_success":{"N":"1729454542656"},"stations_updated":{"N":"290"}}}"#,
+        );
+
+        let status = get_fetch_status(&client, FETCH_STATUS_TABLE, "emilia_romagna")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            status,
+            Some(FetchStatus {
+                last_fetch_success: 1729454542656,
+                stations_updated: 290,
+            })
+        );
+        let body = request_json(request);
+        assert_eq!(body["TableName"], FETCH_STATUS_TABLE);
+        assert_eq!(body["Key"]["region"]["S"], "emilia_romagna");
+    }
+
+    #[tokio::test]
+    async fn get_fetch_status_without_item() {
+        let (client, _request) = mock_dynamodb_client("{}");
+
+        let status = get_fetch_status(&client, FETCH_STATUS_TABLE, "emilia_romagna")
+            .await
+            .unwrap();
+
+        assert_eq!(status, None);
+    }
+
+    #[test]
+    fn fetch_status_message_uses_rome_time() {
+        let status = FetchStatus {
+            last_fetch_success: 1729454542656,
+            stations_updated: 290,
+        };
+        assert_eq!(
+            status.message(),
+            "Ultimo aggiornamento dei dati: 20-10-2024 22:02 (290 stazioni aggiornate)"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use chrono::DateTime;
+use chrono_tz::{Europe::Rome, Tz};
+
+/// Timestamps below this are in seconds: in milliseconds it would be 1973
+const MILLIS_THRESHOLD: i64 = 100_000_000_000;
+
+/// Converts a Unix timestamp, in seconds or milliseconds, to Rome local time
+pub(crate) fn to_rome(timestamp: i64) -> Option<DateTime<Tz>> {
+    let utc = if timestamp.abs() < MILLIS_THRESHOLD {
+        DateTime::from_timestamp(timestamp, 0)
+    } else {
+        DateTime::from_timestamp_millis(timestamp)
+    };
+    utc.map(|datetime| datetime.with_timezone(&Rome))
+}
+
+/// `dd-mm-YYYY HH:MM` in Rome local time, `None` when out of range
+pub(crate) fn format_rome(timestamp: i64) -> Option<String> {
+    to_rome(timestamp).map(|datetime| datetime.format("%d-%m-%Y %H:%M").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_rome_uses_winter_offset() {
+        // 2024-01-15 12:00 UTC
+        assert_eq!(format_rome(1705320000000).unwrap(), "15-01-2024 13:00");
+    }
+
+    #[test]
+    fn format_rome_uses_summer_offset() {
+        // 2024-07-15 12:00 UTC
+        assert_eq!(format_rome(1721044800000).unwrap(), "15-07-2024 14:00");
+    }
+
+    #[test]
+    fn format_rome_across_spring_forward() {
+        // 2024-03-31 00:59 UTC and 01:00 UTC: local clocks jump from 01:59 to 03:00
+        assert_eq!(format_rome(1711846740000).unwrap(), "31-03-2024 01:59");
+        assert_eq!(format_rome(1711846800000).unwrap(), "31-03-2024 03:00");
+    }
+
+    #[test]
+    fn format_rome_across_fall_back() {
+        // 2024-10-27 00:30 UTC and 01:30 UTC both read 02:30 locally
+        assert_eq!(format_rome(1729989000000).unwrap(), "27-10-2024 02:30");
+        assert_eq!(format_rome(1729992600000).unwrap(), "27-10-2024 02:30");
+    }
+
+    #[test]
+    fn to_rome_accepts_seconds_and_millis() {
+        assert_eq!(to_rome(1705320000), to_rome(1705320000000));
+    }
+}
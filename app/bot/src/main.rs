@@ -1,18 +1,27 @@
-use aws_config::BehaviorVersion;
-use aws_sdk_dynamodb::Client as DynamoDbClient;
+use anyhow::anyhow;
 use lambda_runtime::{service_fn, Error as LambdaError, LambdaEvent};
 use serde_json::{json, Value};
+use std::{any::Any, future::Future, ops::ControlFlow};
 use teloxide::{
-    dispatching::{HandlerExt, UpdateFilterExt},
-    dptree::deps,
+    dispatching::HandlerExt,
+    dptree::{deps, di::DependencyMap},
     prelude::{dptree, Bot, Requester, Update},
     respond,
-    types::{Me, Message},
+    types::{Me, Message, UpdateKind},
+    RequestError,
 };
-use tracing::{info, instrument};
+use tracing::{error, info, instrument, warn};
 use tracing_subscriber::EnvFilter;
+
+use commands::utils::{classify_request_error, RequestErrorKind};
+mod chat;
 mod commands;
+mod fetch_status;
+mod local_time;
+mod maintenance;
 mod station;
+#[cfg(test)]
+mod test_utils;
 
 #[tokio::main]
 async fn main() -> Result<(), LambdaError> {
@@ -32,38 +41,342 @@ async fn main() -> Result<(), LambdaError> {
 
 #[instrument]
 async fn lambda_handler(event: LambdaEvent<Value>) -> Result<Value, LambdaError> {
-    let bot = Bot::from_env();
-    let me: Me = bot.get_me().await?;
     info!("{:?}", event.payload);
+    if event.payload.get("action").is_some() {
+        let action: maintenance::Action = serde_json::from_value(event.payload)?;
+        maintenance::run(Bot::from_env(), action).await?;
+        return Ok(lambda_response("Maintenance action executed"));
+    }
+    // A body that can't be parsed will never be: answer 200 so that Telegram
+    // stops retrying the webhook, while infra errors below still fail.
+    let update = match parse_update(&event.payload) {
+        Ok(update) => update,
+        Err(e) => {
+            warn!(error = %e, "Discarding unprocessable update");
+            return Ok(lambda_response("Update discarded"));
+        }
+    };
 
-    let outer_json: Value = serde_json::from_value(
-        event
-            .payload
-            .get("body")
-            .ok_or_else(|| LambdaError::from("Missing 'body' in event payload"))?
-            .clone(),
-    )?;
-    let inner_json_str = outer_json
-        .as_str()
-        .ok_or_else(|| LambdaError::from("Expected 'body' to be a string"))?;
-    let update: Update = serde_json::from_str(inner_json_str)?;
+    handle_update(Bot::from_env(), update).await
+}
 
-    let handler = Update::filter_message()
+async fn handle_update(bot: Bot, update: Update) -> Result<Value, LambdaError> {
+    // Failing to reach Telegram is transient: let the webhook be retried
+    let me: Me = bot.get_me().await?;
+
+    let handler = dptree::filter_map(incoming_message)
+        .branch(
+            dptree::filter(|_: Message| commands::maintenance_mode())
+                .endpoint(commands::maintenance_handler),
+        )
+        .branch(
+            dptree::filter(|msg: Message| commands::admin::is_admin(&msg))
+                .filter_command::<commands::admin::AdminCommand>()
+                .endpoint(commands::admin::admin_commands_handler),
+        )
         .branch(
             dptree::entry()
                 .filter_command::<commands::BaseCommand>()
                 .endpoint(commands::base_commands_handler),
         )
-        .branch(dptree::endpoint(|msg: Message, bot: Bot| async move {
-            let shared_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-            let dynamodb_client = DynamoDbClient::new(&shared_config);
-            commands::message_handler(&bot, &msg, dynamodb_client).await?;
-            respond(())
-        }));
-
-    handler.dispatch(deps![me, bot, update]).await;
-    Ok(json!({
-        "message": "Lambda executed successfully",
+        .branch(
+            dptree::filter(|msg: Message| msg.text().is_some()).endpoint(
+                |msg: Message, bot: Bot| async move {
+                    let dynamodb_client = commands::utils::dynamodb_client().await;
+                    commands::message_handler(&bot, &msg, dynamodb_client).await?;
+                    respond(())
+                },
+            ),
+        );
+
+    let chat_id = update.chat().map(|chat| chat.id.0);
+    let outcome = catch_panic(async move { handler.dispatch(deps![me, bot, update]).await }).await;
+    Ok(match outcome {
+        Some(outcome) => {
+            if let (Some(chat_id), true) = (chat_id, bot_removed_from_chat(&outcome)) {
+                let dynamodb_client = commands::utils::dynamodb_client().await;
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                if let Err(e) =
+                    chat::mark_chat_blocked(&dynamodb_client, chat::CHATS_TABLE, chat_id, now_ms)
+                        .await
+                {
+                    error!(error = %e, "Error marking chat as blocked");
+                }
+            }
+            dispatch_response(outcome)
+        }
+        None => lambda_response("Update discarded"),
+    })
+}
+
+/// The reply failed because the bot can no longer write to the chat
+fn bot_removed_from_chat(outcome: &ControlFlow<Result<(), RequestError>, DependencyMap>) -> bool {
+    matches!(
+        outcome,
+        ControlFlow::Break(Err(e)) if classify_request_error(e) == RequestErrorKind::Blocked
+    )
+}
+
+/// Runs `future` on its own task so that a panic in a handler is logged as a
+/// structured error instead of crashing the invocation; `None` on panic.
+async fn catch_panic<F>(future: F) -> Option<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match tokio::spawn(future).await {
+        Ok(output) => Some(output),
+        Err(e) if e.is_panic() => {
+            error!(panic = panic_message(e.into_panic()), "Handler panicked");
+            None
+        }
+        Err(e) => {
+            error!(error = %e, "Handler task cancelled");
+            None
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map_or_else(
+            || "unknown panic".to_string(),
+            |message| message.to_string(),
+        ),
+    }
+}
+
+/// Once the update has been read, handler failures are only logged: a 5xx
+/// would make Telegram retry an update that will fail the same way.
+fn dispatch_response(outcome: ControlFlow<Result<(), RequestError>, DependencyMap>) -> Value {
+    match outcome {
+        ControlFlow::Break(Ok(())) => {}
+        ControlFlow::Break(Err(e)) => error!(error = %e, "Error handling update"),
+        ControlFlow::Continue(_) => info!("Update kind not handled"),
+    }
+    lambda_response("Lambda executed successfully")
+}
+
+fn parse_update(payload: &Value) -> anyhow::Result<Update> {
+    let body = payload
+        .get("body")
+        .ok_or_else(|| anyhow!("Missing 'body' in event payload"))?
+        .as_str()
+        .ok_or_else(|| anyhow!("Expected 'body' to be a string"))?;
+    Ok(serde_json::from_str(body)?)
+}
+
+fn lambda_response(message: &str) -> Value {
+    json!({
+        "message": message,
         "statusCode": 200,
-    }))
+    })
+}
+
+/// Messages and channel posts share the same handlers: the bot can be added as
+/// admin of a channel and answer station queries posted there.
+fn incoming_message(update: Update) -> Option<Message> {
+    match update.kind {
+        UpdateKind::Message(msg) | UpdateKind::ChannelPost(msg) => Some(msg),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::mock_telegram_server;
+    use lambda_runtime::Context;
+    use teloxide::ApiError;
+
+    #[test]
+    fn incoming_message_accepts_channel_post() {
+        let update: Update = serde_json::from_str(
+            r#"{
+                "update_id": 1,
+                "channel_post": {
+                    "message_id": 10,
+                    "date": 1729454542,
+                    "chat": {"id": -1001234567890, "type": "channel", "title": "Fiumi"},
+                    "text": "Cesena"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let msg = incoming_message(update).unwrap();
+        assert!(msg.chat.is_channel());
+        assert_eq!(msg.text(), Some("Cesena"));
+    }
+
+    #[test]
+    fn incoming_message_accepts_private_message() {
+        let update: Update = serde_json::from_str(
+            r#"{
+                "update_id": 2,
+                "message": {
+                    "message_id": 11,
+                    "date": 1729454542,
+                    "chat": {"id": 42, "type": "private", "first_name": "Mario"},
+                    "from": {"id": 42, "is_bot": false, "first_name": "Mario"},
+                    "text": "/start"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(incoming_message(update).is_some());
+    }
+
+    #[test]
+    fn incoming_message_ignores_other_updates() {
+        let update: Update = serde_json::from_str(
+            r#"{
+                "update_id": 3,
+                "edited_channel_post": {
+                    "message_id": 10,
+                    "date": 1729454542,
+                    "edit_date": 1729454600,
+                    "chat": {"id": -1001234567890, "type": "channel", "title": "Fiumi"},
+                    "text": "Cesena"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(incoming_message(update).is_none());
+    }
+
+    #[test]
+    fn parse_update_reads_string_body() {
+        let payload = json!({
+            "body": r#"{"update_id": 4, "message": {"message_id": 1, "date": 1729454542, "chat": {"id": 42, "type": "private", "first_name": "Mario"}, "text": "Cesena"}}"#
+        });
+        assert!(parse_update(&payload).is_ok());
+    }
+
+    #[test]
+    fn parse_update_rejects_invalid_body() {
+        assert!(parse_update(&json!({})).is_err());
+        assert!(parse_update(&json!({"body": 42})).is_err());
+        assert!(parse_update(&json!({"body": "{not json"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn lambda_handler_acknowledges_missing_body() {
+        let event = LambdaEvent::new(json!({}), Context::default());
+        let response = lambda_handler(event).await.unwrap();
+        assert_eq!(response["statusCode"], 200);
+    }
+
+    #[tokio::test]
+    async fn lambda_handler_acknowledges_malformed_body() {
+        let event = LambdaEvent::new(json!({"body": "{not json"}), Context::default());
+        let response = lambda_handler(event).await.unwrap();
+        assert_eq!(response["statusCode"], 200);
+    }
+
+    #[test]
+    fn dispatch_response_acknowledges_handler_errors() {
+        let outcome = ControlFlow::Break(Err(RequestError::Api(ApiError::BotBlocked)));
+        assert_eq!(dispatch_response(outcome)["statusCode"], 200);
+    }
+
+    #[test]
+    fn dispatch_response_acknowledges_unhandled_updates() {
+        let outcome = ControlFlow::Continue(DependencyMap::new());
+        assert_eq!(dispatch_response(outcome)["statusCode"], 200);
+    }
+
+    #[tokio::test]
+    async fn handle_update_fails_when_telegram_is_unreachable() {
+        let update: Update = serde_json::from_str(
+            r#"{
+                "update_id": 5,
+                "message": {
+                    "message_id": 1,
+                    "date": 1729454542,
+                    "chat": {"id": 42, "type": "private", "first_name": "Mario"},
+                    "text": "Cesena"
+                }
+            }"#,
+        )
+        .unwrap();
+        let bot = Bot::new("42:token").set_api_url("http://127.0.0.1:9".parse().unwrap());
+
+        assert!(handle_update(bot, update).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handle_update_answers_channel_post() {
+        let update: Update = serde_json::from_str(
+            r#"{
+                "update_id": 6,
+                "channel_post": {
+                    "message_id": 10,
+                    "date": 1729454542,
+                    "chat": {"id": -1001234567890, "type": "channel", "title": "Fiumi"},
+                    "text": "/chatinfo"
+                }
+            }"#,
+        )
+        .unwrap();
+        let (url, requests) = mock_telegram_server();
+        let bot = Bot::new("42:token").set_api_url(url);
+
+        let response = handle_update(bot, update).await.unwrap();
+
+        assert_eq!(response["statusCode"], 200);
+        let (method, _) = requests.recv().unwrap();
+        assert_eq!(method, "getme");
+        let (method, body) = requests.recv().unwrap();
+        assert_eq!(method, "sendmessage");
+        assert_eq!(body["chat_id"], -1001234567890_i64);
+        assert!(body["text"].as_str().unwrap().contains("canale"));
+    }
+
+    #[tokio::test]
+    async fn catch_panic_returns_output() {
+        assert_eq!(catch_panic(async { 42 }).await, Some(42));
+    }
+
+    #[tokio::test]
+    async fn catch_panic_catches_panicking_handler() {
+        let outcome = catch_panic(async {
+            if true {
+                panic!("handler exploded");
+            }
+        })
+        .await;
+        assert_eq!(outcome, None);
+    }
+
+    #[test]
+    fn panic_message_reads_str_and_string_payloads() {
+        assert_eq!(panic_message(Box::new("static")), "static");
+        assert_eq!(panic_message(Box::new("owned".to_string())), "owned");
+        assert_eq!(panic_message(Box::new(1)), "unknown panic");
+    }
+
+    #[test]
+    fn bot_removed_from_chat_detects_blocked_bot() {
+        assert!(bot_removed_from_chat(&ControlFlow::Break(Err(
+            RequestError::Api(ApiError::BotBlocked)
+        ))));
+        assert!(bot_removed_from_chat(&ControlFlow::Break(Err(
+            RequestError::Api(ApiError::BotKickedFromSupergroup)
+        ))));
+    }
+
+    #[test]
+    fn bot_removed_from_chat_ignores_other_outcomes() {
+        assert!(!bot_removed_from_chat(&ControlFlow::Break(Ok(()))));
+        assert!(!bot_removed_from_chat(&ControlFlow::Break(Err(
+            RequestError::Api(ApiError::MessageTextIsEmpty)
+        ))));
+        assert!(!bot_removed_from_chat(&ControlFlow::Continue(
+            DependencyMap::new()
+        )));
+    }
 }
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use teloxide::{
+    payloads::SetWebhookSetters,
+    prelude::{Bot, Requester},
+    types::AllowedUpdate,
+};
+
+/// Maintenance tasks run by invoking the Lambda directly, e.g.
+/// `{"action": "set_webhook", "url": "https://...", "secret": "..."}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum Action {
+    /// The webhook is normally registered by the Pulumi `Webhook` resource,
+    /// whose `react_on` is the source of truth for the update kinds: without
+    /// `allowed_updates` Telegram keeps the list already registered
+    SetWebhook {
+        url: String,
+        secret: String,
+        #[serde(default)]
+        allowed_updates: Option<Vec<AllowedUpdate>>,
+    },
+}
+
+pub(crate) async fn run(bot: Bot, action: Action) -> Result<()> {
+    match action {
+        Action::SetWebhook {
+            url,
+            secret,
+            allowed_updates,
+        } => {
+            set_webhook_request(&bot, &url, &secret, allowed_updates)?.await?;
+        }
+    }
+    Ok(())
+}
+
+/// Telegram sends `secret` back in the `X-Telegram-Bot-Api-Secret-Token`
+/// header of every webhook call
+fn set_webhook_request(
+    bot: &Bot,
+    url: &str,
+    secret: &str,
+    allowed_updates: Option<Vec<AllowedUpdate>>,
+) -> Result<<Bot as Requester>::SetWebhook> {
+    let valid_secret = (1..=256).contains(&secret.len())
+        && secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid_secret {
+        return Err(anyhow!(
+            "The webhook secret must be 1-256 characters of A-Z, a-z, 0-9, _ and -"
+        ));
+    }
+
+    let request = bot.set_webhook(url.parse()?).secret_token(secret);
+    Ok(match allowed_updates {
+        Some(allowed_updates) => request.allowed_updates(allowed_updates),
+        None => request,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teloxide::requests::HasPayload;
+
+    #[test]
+    fn parse_set_webhook_action() {
+        let action: Action = serde_json::from_str(
+            r#"{"action": "set_webhook", "url": "https://erfiume.thedodo.xyz/erfiume_bot", "secret": "s3cr3t"}"#,
+        )
+        .unwrap();
+        let Action::SetWebhook {
+            url,
+            secret,
+            allowed_updates,
+        } = action;
+        assert_eq!(url, "https://erfiume.thedodo.xyz/erfiume_bot");
+        assert_eq!(secret, "s3cr3t");
+        assert_eq!(allowed_updates, None);
+    }
+
+    #[test]
+    fn parse_set_webhook_action_with_allowed_updates() {
+        let action: Action = serde_json::from_str(
+            r#"{"action": "set_webhook", "url": "https://erfiume.thedodo.xyz/erfiume_bot", "secret": "s3cr3t", "allowed_updates": ["message", "channel_post"]}"#,
+        )
+        .unwrap();
+        let Action::SetWebhook {
+            allowed_updates, ..
+        } = action;
+        assert_eq!(
+            allowed_updates,
+            Some(vec![AllowedUpdate::Message, AllowedUpdate::ChannelPost])
+        );
+    }
+
+    #[test]
+    fn set_webhook_request_keeps_registered_updates_by_default() {
+        let bot = Bot::new("42:token");
+        let url = "https://erfiume.thedodo.xyz/erfiume_bot";
+
+        let request = set_webhook_request(&bot, url, "s3cr3t", None).unwrap();
+        assert_eq!(request.payload_ref().allowed_updates, None);
+
+        let request =
+            set_webhook_request(&bot, url, "s3cr3t", Some(vec![AllowedUpdate::Message])).unwrap();
+        assert_eq!(
+            request.payload_ref().allowed_updates,
+            Some(vec![AllowedUpdate::Message])
+        );
+    }
+
+    #[test]
+    fn set_webhook_request_carries_the_secret() {
+        let bot = Bot::new("42:token");
+        let request = set_webhook_request(
+            &bot,
+            "https://erfiume.thedodo.xyz/erfiume_bot",
+            "s3cr3t-Token_1",
+            None,
+        )
+        .unwrap();
+
+        let payload = request.payload_ref();
+        assert_eq!(payload.secret_token.as_deref(), Some("s3cr3t-Token_1"));
+        assert_eq!(
+            payload.url.as_str(),
+            "https://erfiume.thedodo.xyz/erfiume_bot"
+        );
+    }
+
+    #[test]
+    fn set_webhook_request_rejects_invalid_secret() {
+        let bot = Bot::new("42:token");
+        let url = "https://erfiume.thedodo.xyz/erfiume_bot";
+        assert!(set_webhook_request(&bot, url, "", None).is_err());
+        assert!(set_webhook_request(&bot, url, "not valid!", None).is_err());
+        assert!(set_webhook_request(&bot, "not a url", "s3cr3t", None).is_err());
+    }
+}
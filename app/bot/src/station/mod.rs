@@ -1,10 +1,26 @@
 pub(crate) mod search;
+pub(crate) mod series;
 
-use chrono::{DateTime, TimeZone};
-use chrono_tz::Europe::Rome;
+use chrono::{SecondsFormat, Utc};
 use serde::Deserialize;
 
+use crate::local_time::{format_rome, to_rome};
+
 const UNKNOWN_VALUE: f64 = -9999.0;
+/// Hydrometric levels, the only variable stored before `unit` was introduced
+pub(crate) const DEFAULT_UNIT: &str = "m";
+const INVALID_THRESHOLDS_CAVEAT: &str =
+    "⚠️ Le soglie pubblicate dalla fonte non sono in ordine crescente: l'allerta potrebbe non essere affidabile";
+const MAX_LISTED_STATIONS: usize = 30;
+pub(crate) const STALE_AFTER_HOURS: i64 = 6;
+/// Slower rises are treated as noise and get no threshold estimate
+const MIN_RISE_METERS_PER_HOUR: f64 = 0.01;
+const MAX_ESTIMATE_HOURS: f64 = 24.0;
+/// Readings needed before extrapolating a trend, all within `TREND_WINDOW_HOURS`
+const MIN_TREND_POINTS: usize = 4;
+const TREND_WINDOW_HOURS: i64 = 3;
+pub(crate) const SOURCE_URL: &str =
+    "https://allertameteo.regione.emilia-romagna.it/livello-idrometrico";
 
 #[derive(Deserialize)]
 #[allow(dead_code)]
@@ -19,48 +35,505 @@ pub struct Stazione {
     soglia2: f64,
     soglia3: f64,
     value: f64,
+    previous_value: Option<f64>,
+    previous_timestamp: Option<i64>,
+    /// False when the source published non-monotonic thresholds
+    thresholds_valid: bool,
+    /// Measurement unit of `value` and the thresholds, e.g. `m`
+    unit: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageFormat {
+    /// Multi-line message with thresholds
+    Detailed,
+    /// Single line, e.g. `Cesena 2.20m 🟠 (22:02)`
+    Compact,
+}
+
+impl MessageFormat {
+    pub fn from_attr(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "dettagliato" => Some(Self::Detailed),
+            "compatto" => Some(Self::Compact),
+            _ => None,
+        }
+    }
+
+    pub fn as_attr(&self) -> &'static str {
+        match self {
+            Self::Detailed => "dettagliato",
+            Self::Compact => "compatto",
+        }
+    }
 }
 
 impl Stazione {
-    pub fn create_station_message(&self) -> String {
-        let timestamp_secs = self.timestamp / 1000;
-        let naive_datetime = DateTime::from_timestamp(timestamp_secs, 0).unwrap();
-        let datetime_in_tz: DateTime<chrono_tz::Tz> =
-            Rome.from_utc_datetime(&naive_datetime.naive_utc());
-        let timestamp_formatted = datetime_in_tz.format("%d-%m-%Y %H:%M").to_string();
+    pub fn create_station_message(&self, format: MessageFormat) -> String {
+        self.create_station_message_with_series(format, &[])
+    }
 
-        let value = self.value;
+    /// Like `create_station_message`, adding to the detailed format the
+    /// threshold estimate extrapolated from the recent `(timestamp, value)`
+    /// points of the station
+    pub fn create_station_message_with_series(
+        &self,
+        format: MessageFormat,
+        points: &[(i64, f64)],
+    ) -> String {
+        let datetime_in_tz = to_rome(self.timestamp).unwrap();
+
+        let alarm = self.alarm();
+        let unknown = self.value == UNKNOWN_VALUE;
+
+        match format {
+            MessageFormat::Detailed => {
+                let value_str = if unknown {
+                    "non disponibile".to_string()
+                } else {
+                    format!("{}{}", self.value, self.unit)
+                };
+                let message = format!(
+                    "Stazione: {}\nValore: {} {}\nSoglia Gialla: {}\nSoglia Arancione: {}\nSoglia Rossa: {}\nUltimo rilevamento: {}",
+                    self.nomestaz,
+                    value_str,
+                    alarm,
+                    threshold_str(self.soglia1),
+                    threshold_str(self.soglia2),
+                    threshold_str(self.soglia3),
+                    datetime_in_tz.format("%d-%m-%Y %H:%M")
+                );
+                let message = match self.trend_message(points) {
+                    Some(trend) => format!("{}\n{}", message, trend),
+                    None => message,
+                };
+                if self.thresholds_valid {
+                    message
+                } else {
+                    format!("{}\n{}", message, INVALID_THRESHOLDS_CAVEAT)
+                }
+            }
+            MessageFormat::Compact if unknown => {
+                format!("{} n/d ({})", self.nomestaz, datetime_in_tz.format("%H:%M"))
+            }
+            MessageFormat::Compact => format!(
+                "{} {:.2}{} {} ({})",
+                self.nomestaz,
+                self.value,
+                self.unit,
+                alarm,
+                datetime_in_tz.format("%H:%M")
+            ),
+        }
+    }
+
+    /// Value with every stored decimal and the exact reading time, both in
+    /// ISO 8601 UTC and Rome local time
+    pub fn precise_message(&self) -> String {
+        let value = if self.value == UNKNOWN_VALUE {
+            "non disponibile".to_string()
+        } else {
+            format!("{}{}", self.value, self.unit)
+        };
+        let (utc, rome) = match to_rome(self.timestamp) {
+            Some(datetime) => (
+                datetime
+                    .with_timezone(&Utc)
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+                datetime.format("%d-%m-%Y %H:%M:%S%.3f").to_string(),
+            ),
+            None => ("non disponibile".to_string(), "non disponibile".to_string()),
+        };
+        format!(
+            "Stazione: {}\nValore: {}\nRilevamento UTC: {}\nRilevamento ora italiana: {}",
+            self.nomestaz, value, utc, rome
+        )
+    }
+
+    /// Change since the previous stored reading, e.g. `+0.15m nelle ultime 2h`
+    pub fn variation_message(&self) -> String {
+        if self.value == UNKNOWN_VALUE {
+            return format!("{}: valore attuale non disponibile", self.nomestaz);
+        }
+        match (self.previous_value, self.previous_timestamp) {
+            (Some(previous), Some(previous_timestamp))
+                if previous != UNKNOWN_VALUE && previous_timestamp < self.timestamp =>
+            {
+                format!(
+                    "{}: {:+.2}{} nelle ultime {} (attuale {:.2}{})",
+                    self.nomestaz,
+                    self.value - previous,
+                    self.unit,
+                    format_elapsed((self.timestamp - previous_timestamp) / 60_000),
+                    self.value,
+                    self.unit
+                )
+            }
+            _ => format!(
+                "{}: nessuna lettura precedente disponibile (attuale {:.2}{})",
+                self.nomestaz, self.value, self.unit
+            ),
+        }
+    }
+
+    /// Minutes since the stored reading, `None` when the record has no
+    /// reading time (the fetcher writes 0)
+    fn reading_age_minutes(&self, now_ms: i64) -> Option<i64> {
+        (self.timestamp > 0).then(|| (now_ms - self.timestamp).max(0) / 60_000)
+    }
+
+    /// Age of the stored reading, flagged once older than `STALE_AFTER_HOURS`
+    pub fn latency_message(&self, now_ms: i64) -> String {
+        let minutes = match self.reading_age_minutes(now_ms) {
+            Some(minutes) => minutes,
+            None => {
+                return format!(
+                    "{}: orario dell'ultima lettura non disponibile",
+                    self.nomestaz
+                )
+            }
+        };
+        let stale = if minutes > STALE_AFTER_HOURS * 60 {
+            "\n⚠️ Dato non aggiornato, il valore potrebbe non essere affidabile"
+        } else {
+            ""
+        };
+        format!(
+            "{}: ultima lettura {} fa ({}){}",
+            self.nomestaz,
+            format_elapsed(minutes),
+            format_rome(self.timestamp).unwrap_or_default(),
+            stale
+        )
+    }
 
+    /// Latitude and longitude when both parse to a valid, non-zero position
+    pub fn coordinates(&self) -> Option<(f64, f64)> {
+        parse_coordinates(&self.lat, &self.lon)
+    }
+
+    fn red_ratio(&self) -> f64 {
+        if self.soglia3 > 0.0 {
+            self.value / self.soglia3
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimate of when the next threshold will be crossed, fitting the
+    /// readings of the last `TREND_WINDOW_HOURS`; only shown when there are at
+    /// least `MIN_TREND_POINTS` of them and each one is higher than the last
+    fn trend_message(&self, points: &[(i64, f64)]) -> Option<String> {
+        if self.value == UNKNOWN_VALUE {
+            return None;
+        }
+        let since = self.timestamp - TREND_WINDOW_HOURS * 3_600_000;
+        let mut recent: Vec<(i64, f64)> = points
+            .iter()
+            .copied()
+            .filter(|(ts, value)| *ts >= since && *ts <= self.timestamp && *value != UNKNOWN_VALUE)
+            .collect();
+        recent.sort_by_key(|(ts, _)| *ts);
+        if recent.len() < MIN_TREND_POINTS || recent.windows(2).any(|pair| pair[1].1 <= pair[0].1) {
+            return None;
+        }
+        let slope = linear_slope(&recent)?;
+        let (name, threshold) = [
+            ("gialla", self.soglia1),
+            ("arancione", self.soglia2),
+            ("rossa", self.soglia3),
+        ]
+        .into_iter()
+        .find(|(_, threshold)| *threshold != UNKNOWN_VALUE && *threshold > self.value)?;
+        let hours = hours_to_threshold(self.value, slope * 3_600_000.0, threshold)?;
+        Some(format!(
+            "A questo ritmo supererà la soglia {} tra ~{}",
+            name,
+            format_elapsed((hours * 60.0).round().max(1.0) as i64)
+        ))
+    }
+
+    /// Data-pipeline problems of the station: a missing or zeroed value and
+    /// a reading older than `max_age_hours`
+    fn diagnostic_issues(&self, now_ms: i64, max_age_hours: i64) -> Vec<&'static str> {
+        let mut issues = Vec::new();
+        if self.value == UNKNOWN_VALUE || self.value == 0.0 {
+            issues.push("valore mancante");
+        }
+        if now_ms - self.timestamp > max_age_hours * 3_600_000 {
+            issues.push("dato non aggiornato");
+        }
+        issues
+    }
+
+    fn alarm(&self) -> &'static str {
+        match self.alarm_level() {
+            None => "",
+            Some(0) => "🟢",
+            Some(1) => "🟡",
+            Some(2) => "🟠",
+            Some(_) => "🔴",
+        }
+    }
+
+    /// 0 green, 1 yellow, 2 orange, 3 red; `None` when value or thresholds are unknown
+    fn alarm_level(&self) -> Option<u8> {
+        let value = self.value;
         let yellow = self.soglia1;
         let orange = self.soglia2;
         let red = self.soglia3;
 
-        let mut alarm = "🔴";
-        if value <= yellow {
-            alarm = "🟢";
+        if value == UNKNOWN_VALUE || [yellow, orange, red].contains(&UNKNOWN_VALUE) {
+            None
+        } else if value <= yellow {
+            Some(0)
         } else if value > yellow && value <= orange {
-            alarm = "🟡";
+            Some(1)
         } else if value >= orange && value <= red {
-            alarm = "🟠";
+            Some(2)
+        } else {
+            Some(3)
         }
+    }
+}
+
+/// Least-squares slope of `(timestamp ms, value)` points, in value per ms
+fn linear_slope(points: &[(i64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|(t, _)| *t as f64).sum::<f64>() / n;
+    let mean_v = points.iter().map(|(_, v)| v).sum::<f64>() / n;
+    let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), (t, v)| {
+        let dt = *t as f64 - mean_t;
+        (cov + dt * (v - mean_v), var + dt * dt)
+    });
+    if variance == 0.0 {
+        None
+    } else {
+        Some(covariance / variance)
+    }
+}
+
+/// Hours until `threshold` at the given rate, `None` when not rising fast
+/// enough or too far away to be a meaningful estimate
+fn hours_to_threshold(value: f64, meters_per_hour: f64, threshold: f64) -> Option<f64> {
+    if meters_per_hour < MIN_RISE_METERS_PER_HOUR || value >= threshold {
+        return None;
+    }
+    let hours = (threshold - value) / meters_per_hour;
+    (hours <= MAX_ESTIMATE_HOURS).then_some(hours)
+}
+
+fn format_elapsed(minutes: i64) -> String {
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => format!("{}min", minutes),
+        (hours, 0) => format!("{}h", hours),
+        (hours, minutes) => format!("{}h {}min", hours, minutes),
+    }
+}
+
+/// Sources without coordinates publish `0`, which is not a real position
+fn parse_coordinates(lat: &str, lon: &str) -> Option<(f64, f64)> {
+    let lat = lat.trim().replace(',', ".").parse::<f64>().ok()?;
+    let lon = lon.trim().replace(',', ".").parse::<f64>().ok()?;
+    let valid = (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon);
+    (valid && lat != 0.0 && lon != 0.0).then_some((lat, lon))
+}
 
-        let mut value_str = format!("{}", value);
-        if value == UNKNOWN_VALUE {
-            value_str = "non disponibile".to_string();
-            alarm = "";
+fn threshold_str(threshold: f64) -> String {
+    if threshold == UNKNOWN_VALUE {
+        "non disponibile".to_string()
+    } else {
+        threshold.to_string()
+    }
+}
+
+/// Stations with a known value strictly above `threshold`, highest first
+pub(crate) fn stations_above(stations: Vec<Stazione>, threshold: f64) -> Vec<Stazione> {
+    let mut above: Vec<Stazione> = stations
+        .into_iter()
+        .filter(|s| s.value != UNKNOWN_VALUE && s.value > threshold)
+        .collect();
+    above.sort_by(|a, b| b.value.total_cmp(&a.value));
+    above
+}
+
+pub(crate) fn format_stations_above(stations: &[Stazione], threshold: f64) -> String {
+    if stations.is_empty() {
+        return format!("Nessuna stazione sopra {}", threshold);
+    }
+    let mut lines = vec![format!("Stazioni sopra {}:", threshold)];
+    lines.extend(
+        stations
+            .iter()
+            .take(MAX_LISTED_STATIONS)
+            .map(|s| s.create_station_message(MessageFormat::Compact)),
+    );
+    if stations.len() > MAX_LISTED_STATIONS {
+        lines.push(format!(
+            "... e altre {} stazioni",
+            stations.len() - MAX_LISTED_STATIONS
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Stations at yellow or above, worst alarm first and, within the same alarm,
+/// closest to the red threshold first
+pub(crate) fn stations_in_alarm(stations: Vec<Stazione>) -> Vec<Stazione> {
+    let mut in_alarm: Vec<Stazione> = stations
+        .into_iter()
+        .filter(|s| s.alarm_level().is_some_and(|level| level >= 1))
+        .collect();
+    in_alarm.sort_by(|a, b| {
+        b.alarm_level()
+            .cmp(&a.alarm_level())
+            .then_with(|| b.red_ratio().total_cmp(&a.red_ratio()))
+    });
+    in_alarm
+}
+
+pub(crate) fn format_stations_in_alarm(stations: &[Stazione]) -> String {
+    if stations.is_empty() {
+        return "Nessuna stazione sopra la soglia gialla".to_string();
+    }
+    let mut lines = vec!["Stazioni in allerta:".to_string()];
+    lines.extend(
+        stations
+            .iter()
+            .take(MAX_LISTED_STATIONS)
+            .map(|s| s.create_station_message(MessageFormat::Compact)),
+    );
+    if stations.len() > MAX_LISTED_STATIONS {
+        lines.push(format!(
+            "... e altre {} stazioni",
+            stations.len() - MAX_LISTED_STATIONS
+        ));
+    }
+    lines.join("\n")
+}
+
+const CSV_HEADER: &str = "nomestaz,idstazione,value,soglia1,soglia2,soglia3,timestamp";
+
+/// CSV dump of the stations, unknown values are left empty
+pub(crate) fn stations_to_csv(stations: &[Stazione]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for s in stations {
+        let fields = [
+            csv_field(&s.nomestaz),
+            csv_field(&s.idstazione),
+            csv_number(s.value),
+            csv_number(s.soglia1),
+            csv_number(s.soglia2),
+            csv_number(s.soglia3),
+            s.timestamp.to_string(),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_number(value: f64) -> String {
+    if value == UNKNOWN_VALUE {
+        String::new()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Stations with missing or stale data, most stale first
+pub(crate) fn stations_with_issues(
+    stations: Vec<Stazione>,
+    now_ms: i64,
+    max_age_hours: i64,
+) -> Vec<Stazione> {
+    let mut with_issues: Vec<Stazione> = stations
+        .into_iter()
+        .filter(|s| !s.diagnostic_issues(now_ms, max_age_hours).is_empty())
+        .collect();
+    with_issues.sort_by_key(|s| s.timestamp);
+    with_issues
+}
+
+/// One page (starting from 1) of `MAX_LISTED_STATIONS` diagnostic lines
+pub(crate) fn format_diagnostics(
+    stations: &[Stazione],
+    now_ms: i64,
+    max_age_hours: i64,
+    page: usize,
+) -> String {
+    if stations.is_empty() {
+        return "Nessun problema rilevato sui dati delle stazioni".to_string();
+    }
+    let pages = stations.len().div_ceil(MAX_LISTED_STATIONS);
+    let page = page.clamp(1, pages);
+    let mut lines = vec![format!(
+        "Stazioni con dati mancanti o vecchi: {} (pagina {}/{})",
+        stations.len(),
+        page,
+        pages
+    )];
+    lines.extend(
+        stations
+            .iter()
+            .skip((page - 1) * MAX_LISTED_STATIONS)
+            .take(MAX_LISTED_STATIONS)
+            .map(|s| {
+                format!(
+                    "{}: {}, ultimo rilevamento {} fa",
+                    s.nomestaz,
+                    s.diagnostic_issues(now_ms, max_age_hours).join(", "),
+                    format_elapsed((now_ms - s.timestamp).max(0) / 60_000)
+                )
+            }),
+    );
+    if page < pages {
+        lines.push(format!("Pagina successiva: /diagnostica {}", page + 1));
+    }
+    lines.join("\n")
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum StationsSort {
+    Name,
+    Ordinamento,
+}
+
+impl StationsSort {
+    /// `STATIONS_SORT=ordinamento` lists stations following the portal ordering,
+    /// any other value keeps the alphabetical list
+    pub(crate) fn from_env() -> Self {
+        Self::parse(std::env::var("STATIONS_SORT").ok().as_deref())
+    }
+
+    fn parse(value: Option<&str>) -> Self {
+        match value.map(|v| v.trim().to_lowercase()).as_deref() {
+            Some("ordinamento") => Self::Ordinamento,
+            _ => Self::Name,
         }
+    }
+}
 
-        format!(
-            "Stazione: {}\nValore: {} {}\nSoglia Gialla: {}\nSoglia Arancione: {}\nSoglia Rossa: {}\nUltimo rilevamento: {}",
-            self.nomestaz,
-            value_str,
-            alarm,
-            yellow,
-            orange,
-            red,
-            timestamp_formatted
-        )
+pub(crate) fn sort_stations(mut stations: Vec<(String, i32)>, sort: StationsSort) -> Vec<String> {
+    match sort {
+        StationsSort::Name => stations.sort_by(|a, b| a.0.cmp(&b.0)),
+        StationsSort::Ordinamento => {
+            stations.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)))
+        }
     }
+    stations.into_iter().map(|(name, _)| name).collect()
 }
 
 pub fn stations() -> Vec<String> {
@@ -336,6 +809,25 @@ pub fn stations() -> Vec<String> {
 mod tests {
     use super::*;
 
+    fn station_with_value(nomestaz: &str, value: f64) -> Stazione {
+        Stazione {
+            idstazione: "/id/".to_string(),
+            timestamp: 1729454542656,
+            ordinamento: 1,
+            nomestaz: nomestaz.to_string(),
+            lon: "lon".to_string(),
+            lat: "lat".to_string(),
+            soglia1: 1.0,
+            soglia2: 2.0,
+            soglia3: 3.0,
+            value,
+            previous_value: None,
+            previous_timestamp: None,
+            thresholds_valid: true,
+            unit: DEFAULT_UNIT.to_string(),
+        }
+    }
+
     #[test]
     fn create_station_message_with_unknown_value() {
         let station = Stazione {
@@ -349,10 +841,17 @@ mod tests {
             soglia2: 2.0,
             soglia3: 3.0,
             value: UNKNOWN_VALUE,
+            previous_value: None,
+            previous_timestamp: None,
+            thresholds_valid: true,
+            unit: DEFAULT_UNIT.to_string(),
         };
         let expected = "Stazione: Cesena\nValore: non disponibile \nSoglia Gialla: 1\nSoglia Arancione: 2\nSoglia Rossa: 3\nUltimo rilevamento: 20-10-2024 22:02".to_string();
 
-        assert_eq!(station.create_station_message(), expected);
+        assert_eq!(
+            station.create_station_message(MessageFormat::Detailed),
+            expected
+        );
     }
 
     #[test]
@@ -368,9 +867,501 @@ mod tests {
             soglia2: 2.0,
             soglia3: 3.0,
             value: 2.2,
+            previous_value: None,
+            previous_timestamp: None,
+            thresholds_valid: true,
+            unit: DEFAULT_UNIT.to_string(),
         };
-        let expected = "Stazione: Cesena\nValore: 2.2 🟠\nSoglia Gialla: 1\nSoglia Arancione: 2\nSoglia Rossa: 3\nUltimo rilevamento: 20-10-2024 22:02".to_string();
+        let expected = "Stazione: Cesena\nValore: 2.2m 🟠\nSoglia Gialla: 1\nSoglia Arancione: 2\nSoglia Rossa: 3\nUltimo rilevamento: 20-10-2024 22:02".to_string();
+
+        assert_eq!(
+            station.create_station_message(MessageFormat::Detailed),
+            expected
+        );
+    }
+
+    fn stations_fixture() -> Vec<(String, i32)> {
+        vec![
+            ("Cesena".to_string(), 2),
+            ("S. Carlo".to_string(), 1),
+            ("Borello".to_string(), 2),
+            ("Ponte Vico".to_string(), 3),
+        ]
+    }
+
+    #[test]
+    fn stations_sort_parse_defaults_to_name() {
+        assert_eq!(StationsSort::parse(None), StationsSort::Name);
+        assert_eq!(StationsSort::parse(Some("nome")), StationsSort::Name);
+        assert_eq!(
+            StationsSort::parse(Some("Ordinamento")),
+            StationsSort::Ordinamento
+        );
+    }
+
+    #[test]
+    fn sort_stations_by_name() {
+        assert_eq!(
+            sort_stations(stations_fixture(), StationsSort::Name),
+            vec!["Borello", "Cesena", "Ponte Vico", "S. Carlo"]
+        );
+    }
+
+    #[test]
+    fn sort_stations_by_ordinamento_breaks_ties_by_name() {
+        assert_eq!(
+            sort_stations(stations_fixture(), StationsSort::Ordinamento),
+            vec!["S. Carlo", "Borello", "Cesena", "Ponte Vico"]
+        );
+    }
 
-        assert_eq!(station.create_station_message(), expected);
+    #[test]
+    fn stations_above_filters_and_sorts_descending() {
+        let stations = vec![
+            station_with_value("Cesena", 2.2),
+            station_with_value("S. Carlo", 0.5),
+            station_with_value("Borello", 3.4),
+            station_with_value("Ponte Vico", UNKNOWN_VALUE),
+            station_with_value("Lugo", 2.0),
+        ];
+
+        let names: Vec<String> = stations_above(stations, 2.0)
+            .into_iter()
+            .map(|s| s.nomestaz)
+            .collect();
+        assert_eq!(names, vec!["Borello", "Cesena"]);
+    }
+
+    #[test]
+    fn format_stations_above_caps_the_list() {
+        let stations: Vec<Stazione> = (0..MAX_LISTED_STATIONS + 5)
+            .map(|i| station_with_value(&format!("Stazione {}", i), 5.0))
+            .collect();
+
+        let message = format_stations_above(&stations, 1.0);
+        assert_eq!(message.lines().count(), MAX_LISTED_STATIONS + 2);
+        assert!(message.ends_with("... e altre 5 stazioni"));
+    }
+
+    #[test]
+    fn format_stations_above_without_results() {
+        assert_eq!(
+            format_stations_above(&[], 4.5),
+            "Nessuna stazione sopra 4.5"
+        );
+    }
+
+    #[test]
+    fn format_station_message_compact() {
+        let station = station_with_value("Cesena", 2.2);
+        assert_eq!(
+            station.create_station_message(MessageFormat::Compact),
+            "Cesena 2.20m 🟠 (22:02)"
+        );
+    }
+
+    #[test]
+    fn create_station_message_shows_unit() {
+        let mut station = station_with_value("Cesena", 12.5);
+        station.unit = "m³/s".to_string();
+        assert!(station
+            .create_station_message(MessageFormat::Compact)
+            .starts_with("Cesena 12.50m³/s "));
+        assert!(station
+            .create_station_message(MessageFormat::Detailed)
+            .contains("Valore: 12.5m³/s "));
+    }
+
+    #[test]
+    fn parse_coordinates_accepts_decimal_strings() {
+        assert_eq!(parse_coordinates("44.13", " 12.24 "), Some((44.13, 12.24)));
+        assert_eq!(parse_coordinates("44,13", "12,24"), Some((44.13, 12.24)));
+    }
+
+    #[test]
+    fn parse_coordinates_skips_missing_positions() {
+        assert_eq!(parse_coordinates("0", "0"), None);
+        assert_eq!(parse_coordinates("44.13", "0"), None);
+        assert_eq!(parse_coordinates("", ""), None);
+        assert_eq!(parse_coordinates("lat", "lon"), None);
+        assert_eq!(parse_coordinates("144.13", "12.24"), None);
+    }
+
+    #[test]
+    fn create_station_message_across_spring_forward() {
+        let mut station = station_with_value("Cesena", 2.2);
+        // 2024-03-31 00:30 UTC is 01:30 CET, 01:30 UTC is 03:30 CEST
+        station.timestamp = 1711845000000;
+        assert!(station
+            .create_station_message(MessageFormat::Detailed)
+            .ends_with("Ultimo rilevamento: 31-03-2024 01:30"));
+        station.timestamp = 1711848600000;
+        assert!(station
+            .create_station_message(MessageFormat::Detailed)
+            .ends_with("Ultimo rilevamento: 31-03-2024 03:30"));
+    }
+
+    #[test]
+    fn create_station_message_across_fall_back() {
+        let mut station = station_with_value("Cesena", 2.2);
+        // 2024-10-27 00:59 UTC is 02:59 CEST, 01:00 UTC is 02:00 CET
+        station.timestamp = 1729990740000;
+        assert_eq!(
+            station.create_station_message(MessageFormat::Compact),
+            "Cesena 2.20m 🟠 (02:59)"
+        );
+        station.timestamp = 1729990800000;
+        assert_eq!(
+            station.create_station_message(MessageFormat::Compact),
+            "Cesena 2.20m 🟠 (02:00)"
+        );
+    }
+
+    #[test]
+    fn format_station_message_compact_with_unknown_value() {
+        let station = station_with_value("Cesena", UNKNOWN_VALUE);
+        assert_eq!(
+            station.create_station_message(MessageFormat::Compact),
+            "Cesena n/d (22:02)"
+        );
+    }
+
+    #[test]
+    fn message_format_attr_roundtrip() {
+        for format in [MessageFormat::Detailed, MessageFormat::Compact] {
+            assert_eq!(MessageFormat::from_attr(format.as_attr()), Some(format));
+        }
+        assert_eq!(
+            MessageFormat::from_attr(" Compatto "),
+            Some(MessageFormat::Compact)
+        );
+        assert_eq!(MessageFormat::from_attr("breve"), None);
+    }
+
+    #[test]
+    fn create_station_message_with_unknown_thresholds_has_no_alarm() {
+        let mut station = station_with_value("Cesena", 2.5);
+        station.soglia1 = UNKNOWN_VALUE;
+        station.soglia2 = UNKNOWN_VALUE;
+        station.soglia3 = UNKNOWN_VALUE;
+        let message = station.create_station_message(MessageFormat::Detailed);
+        assert!(message.contains("Valore: 2.5m \n"));
+        assert!(message.contains("Soglia Rossa: non disponibile"));
+    }
+
+    fn station_with_previous(value: f64, previous_value: f64, elapsed_ms: i64) -> Stazione {
+        let mut station = station_with_value("Cesena", value);
+        station.previous_value = Some(previous_value);
+        station.previous_timestamp = Some(station.timestamp - elapsed_ms);
+        station
+    }
+
+    #[test]
+    fn variation_message_with_positive_delta() {
+        let station = station_with_previous(2.2, 2.05, 2 * 3_600_000);
+        assert_eq!(
+            station.variation_message(),
+            "Cesena: +0.15m nelle ultime 2h (attuale 2.20m)"
+        );
+    }
+
+    #[test]
+    fn variation_message_with_negative_delta() {
+        let station = station_with_previous(1.5, 1.75, 90 * 60_000);
+        assert_eq!(
+            station.variation_message(),
+            "Cesena: -0.25m nelle ultime 1h 30min (attuale 1.50m)"
+        );
+    }
+
+    #[test]
+    fn variation_message_without_previous_value() {
+        let station = station_with_value("Cesena", 1.5);
+        assert_eq!(
+            station.variation_message(),
+            "Cesena: nessuna lettura precedente disponibile (attuale 1.50m)"
+        );
+
+        // Items written for the first time have previous == current
+        let station = station_with_previous(1.5, 1.5, 0);
+        assert_eq!(
+            station.variation_message(),
+            "Cesena: nessuna lettura precedente disponibile (attuale 1.50m)"
+        );
+    }
+
+    const NOW_MS: i64 = 1729454542656;
+
+    fn station_updated_hours_ago(nomestaz: &str, value: f64, hours: i64) -> Stazione {
+        let mut station = station_with_value(nomestaz, value);
+        station.timestamp = NOW_MS - hours * 3_600_000;
+        station
+    }
+
+    #[test]
+    fn latency_message_shows_reading_age() {
+        let mut station = station_updated_hours_ago("Cesena", 2.2, 2);
+        station.timestamp -= 5 * 60_000;
+        let message = station.latency_message(NOW_MS);
+        assert!(message.starts_with("Cesena: ultima lettura 2h 5min fa ("));
+        assert!(!message.contains("Dato non aggiornato"));
+    }
+
+    #[test]
+    fn latency_message_flags_stale_readings() {
+        let station = station_updated_hours_ago("Cesena", 2.2, STALE_AFTER_HOURS + 1);
+        assert!(station
+            .latency_message(NOW_MS)
+            .contains("Dato non aggiornato"));
+    }
+
+    #[test]
+    fn latency_message_without_timestamp() {
+        let mut station = station_with_value("Cesena", 2.2);
+        station.timestamp = 0;
+        assert_eq!(
+            station.latency_message(NOW_MS),
+            "Cesena: orario dell'ultima lettura non disponibile"
+        );
+    }
+
+    #[test]
+    fn reading_age_is_never_negative() {
+        let station = station_updated_hours_ago("Cesena", 2.2, -1);
+        assert_eq!(station.reading_age_minutes(NOW_MS), Some(0));
+    }
+
+    #[test]
+    fn stations_with_issues_detects_stale_and_missing_values() {
+        let stations = vec![
+            station_updated_hours_ago("Fresca", 1.2, 1),
+            station_updated_hours_ago("Vecchia", 1.2, STALE_AFTER_HOURS + 1),
+            station_updated_hours_ago("Zero", 0.0, 1),
+            station_updated_hours_ago("Sconosciuta", UNKNOWN_VALUE, 2),
+            station_updated_hours_ago("Vecchissima", 1.2, 48),
+        ];
+
+        let names: Vec<String> = stations_with_issues(stations, NOW_MS, STALE_AFTER_HOURS)
+            .into_iter()
+            .map(|s| s.nomestaz)
+            .collect();
+        assert_eq!(names, vec!["Vecchissima", "Vecchia", "Sconosciuta", "Zero"]);
+    }
+
+    #[test]
+    fn format_diagnostics_lists_issues() {
+        let stations = vec![station_updated_hours_ago("Cesena", 0.0, 8)];
+        assert_eq!(
+            format_diagnostics(&stations, NOW_MS, STALE_AFTER_HOURS, 1),
+            "Stazioni con dati mancanti o vecchi: 1 (pagina 1/1)\nCesena: valore mancante, dato non aggiornato, ultimo rilevamento 8h fa"
+        );
+        assert_eq!(
+            format_diagnostics(&[], NOW_MS, STALE_AFTER_HOURS, 1),
+            "Nessun problema rilevato sui dati delle stazioni"
+        );
+    }
+
+    #[test]
+    fn format_diagnostics_paginates() {
+        let stations: Vec<Stazione> = (0..MAX_LISTED_STATIONS + 5)
+            .map(|i| station_updated_hours_ago(&format!("Stazione {}", i), 0.0, 1))
+            .collect();
+
+        let first = format_diagnostics(&stations, NOW_MS, STALE_AFTER_HOURS, 1);
+        assert_eq!(first.lines().count(), MAX_LISTED_STATIONS + 2);
+        assert!(first.ends_with("Pagina successiva: /diagnostica 2"));
+
+        let last = format_diagnostics(&stations, NOW_MS, STALE_AFTER_HOURS, 7);
+        assert!(last.starts_with("Stazioni con dati mancanti o vecchi: 35 (pagina 2/2)"));
+        assert_eq!(last.lines().count(), 6);
+    }
+
+    #[test]
+    fn stations_to_csv_writes_header_and_rows() {
+        let stations = vec![
+            station_with_value("Cesena", 2.2),
+            station_with_value("Lugo", UNKNOWN_VALUE),
+        ];
+        assert_eq!(
+            stations_to_csv(&stations),
+            "nomestaz,idstazione,value,soglia1,soglia2,soglia3,timestamp\n\
+             Cesena,/id/,2.2,1,2,3,1729454542656\n\
+             Lugo,/id/,,1,2,3,1729454542656\n"
+        );
+    }
+
+    #[test]
+    fn stations_to_csv_escapes_commas_and_quotes() {
+        let stations = vec![
+            station_with_value("Ponte, Nuovo", 1.0),
+            station_with_value("S. \"Carlo\"", 1.0),
+        ];
+        let csv = stations_to_csv(&stations);
+        assert!(csv.contains("\n\"Ponte, Nuovo\",/id/,"));
+        assert!(csv.contains("\n\"S. \"\"Carlo\"\"\",/id/,"));
+    }
+
+    #[test]
+    fn linear_slope_fits_points() {
+        let hour = 3_600_000;
+        let slope = linear_slope(&[(0, 1.0), (hour, 1.2), (2 * hour, 1.4)]).unwrap();
+        assert!((slope * hour as f64 - 0.2).abs() < 1e-9);
+        assert_eq!(linear_slope(&[(0, 1.0)]), None);
+        assert_eq!(linear_slope(&[(hour, 1.0), (hour, 2.0)]), None);
+    }
+
+    #[test]
+    fn hours_to_threshold_extrapolates_rising_trend() {
+        assert_eq!(hours_to_threshold(1.5, 0.25, 2.0), Some(2.0));
+    }
+
+    #[test]
+    fn hours_to_threshold_without_rising_trend() {
+        assert_eq!(hours_to_threshold(1.5, 0.0, 2.0), None);
+        assert_eq!(hours_to_threshold(1.5, -0.2, 2.0), None);
+        assert_eq!(hours_to_threshold(1.5, 0.001, 2.0), None);
+        assert_eq!(hours_to_threshold(2.5, 0.2, 2.0), None);
+        // Too far away to be meaningful
+        assert_eq!(hours_to_threshold(0.0, 0.05, 2.0), None);
+    }
+
+    /// Readings every 30 minutes ending at the station's own, at `values`
+    fn series_ending_at(station: &Stazione, values: &[f64]) -> Vec<(i64, f64)> {
+        let last = values.len() as i64 - 1;
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (station.timestamp - (last - i as i64) * 30 * 60_000, *value))
+            .collect()
+    }
+
+    #[test]
+    fn create_station_message_with_rising_trend() {
+        let station = station_with_value("Cesena", 1.5);
+        let points = series_ending_at(&station, &[1.125, 1.25, 1.375, 1.5]);
+        assert!(station
+            .create_station_message_with_series(MessageFormat::Detailed, &points)
+            .ends_with("\nA questo ritmo supererà la soglia arancione tra ~2h"));
+    }
+
+    #[test]
+    fn create_station_message_without_series_has_no_trend() {
+        let station = station_with_previous(1.5, 1.25, 3_600_000);
+        assert!(!station
+            .create_station_message(MessageFormat::Detailed)
+            .contains("A questo ritmo"));
+    }
+
+    #[test]
+    fn trend_message_needs_enough_recent_points() {
+        let station = station_with_value("Cesena", 1.5);
+        let points = series_ending_at(&station, &[1.25, 1.375, 1.5]);
+        assert_eq!(station.trend_message(&points), None);
+        // Readings older than the window don't count
+        let points = series_ending_at(&station, &[1.0, 1.05, 1.1, 1.15, 1.2, 1.25, 1.375, 1.5]);
+        assert_eq!(station.trend_message(&points[..4]), None);
+    }
+
+    #[test]
+    fn trend_message_needs_consistent_rises() {
+        let station = station_with_value("Cesena", 1.5);
+        // A single jump after a flat series is noise, not a trend
+        let points = series_ending_at(&station, &[1.0, 1.0, 1.0, 1.5]);
+        assert_eq!(station.trend_message(&points), None);
+        let points = series_ending_at(&station, &[1.125, 1.3, 1.25, 1.5]);
+        assert_eq!(station.trend_message(&points), None);
+        let points = series_ending_at(&station, &[1.875, 1.75, 1.625, 1.5]);
+        assert_eq!(station.trend_message(&points), None);
+    }
+
+    #[test]
+    fn create_station_message_with_invalid_thresholds_has_caveat() {
+        let mut station = station_with_thresholds("Cesena", 2.2, [2.0, 1.0, 3.0]);
+        station.thresholds_valid = false;
+        assert!(station
+            .create_station_message(MessageFormat::Detailed)
+            .ends_with(INVALID_THRESHOLDS_CAVEAT));
+    }
+
+    #[test]
+    fn create_station_message_with_valid_thresholds_has_no_caveat() {
+        let station = station_with_value("Cesena", 2.2);
+        assert!(!station
+            .create_station_message(MessageFormat::Detailed)
+            .contains(INVALID_THRESHOLDS_CAVEAT));
+    }
+
+    fn station_with_thresholds(nomestaz: &str, value: f64, soglie: [f64; 3]) -> Stazione {
+        let mut station = station_with_value(nomestaz, value);
+        [station.soglia1, station.soglia2, station.soglia3] = soglie;
+        station
+    }
+
+    #[test]
+    fn stations_in_alarm_ranks_worst_first() {
+        let stations = vec![
+            station_with_value("Gialla", 1.5),
+            station_with_value("Rossa", 3.5),
+            station_with_thresholds("Arancione bassa", 2.5, [1.0, 2.0, 5.0]),
+            station_with_thresholds("Arancione alta", 4.5, [1.0, 2.0, 5.0]),
+        ];
+        let names: Vec<String> = stations_in_alarm(stations)
+            .into_iter()
+            .map(|s| s.nomestaz)
+            .collect();
+        assert_eq!(
+            names,
+            vec!["Rossa", "Arancione alta", "Arancione bassa", "Gialla"]
+        );
+    }
+
+    #[test]
+    fn stations_in_alarm_drops_green_and_unknown() {
+        let stations = vec![
+            station_with_value("Verde", 0.5),
+            station_with_value("Soglia gialla", 1.0),
+            station_with_value("Sconosciuta", UNKNOWN_VALUE),
+            station_with_thresholds("Senza soglie", 4.0, [UNKNOWN_VALUE; 3]),
+            station_with_value("Gialla", 1.1),
+        ];
+        let names: Vec<String> = stations_in_alarm(stations)
+            .into_iter()
+            .map(|s| s.nomestaz)
+            .collect();
+        assert_eq!(names, vec!["Gialla"]);
+    }
+
+    #[test]
+    fn format_stations_in_alarm_without_results() {
+        assert_eq!(
+            format_stations_in_alarm(&[]),
+            "Nessuna stazione sopra la soglia gialla"
+        );
+    }
+
+    #[test]
+    fn precise_message_keeps_every_decimal_and_iso_timestamp() {
+        let station = station_with_value("Cesena", 2.3456);
+        assert_eq!(
+            station.precise_message(),
+            "Stazione: Cesena\nValore: 2.3456m\nRilevamento UTC: 2024-10-20T20:02:22.656Z\nRilevamento ora italiana: 20-10-2024 22:02:22.656"
+        );
+    }
+
+    #[test]
+    fn precise_message_with_unknown_value() {
+        let station = station_with_value("Cesena", UNKNOWN_VALUE);
+        assert!(station
+            .precise_message()
+            .contains("\nValore: non disponibile\n"));
+    }
+
+    #[test]
+    fn precise_message_with_out_of_range_timestamp() {
+        let mut station = station_with_value("Cesena", 2.2);
+        station.timestamp = i64::MAX;
+        assert!(station.precise_message().ends_with(
+            "\nRilevamento UTC: non disponibile\nRilevamento ora italiana: non disponibile"
+        ));
     }
 }
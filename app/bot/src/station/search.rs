@@ -1,33 +1,133 @@
 use anyhow::{anyhow, Result};
-use aws_sdk_dynamodb::{types::AttributeValue, Client as DynamoDbClient};
-use std::collections::HashMap;
+use aws_sdk_dynamodb::{
+    types::{AttributeValue, ReturnValue},
+    Client as DynamoDbClient,
+};
+use std::{collections::HashMap, future::Future};
+use tracing::warn;
 
-use super::{stations, Stazione, UNKNOWN_VALUE};
+use super::{sort_stations, stations, StationsSort, Stazione, DEFAULT_UNIT, UNKNOWN_VALUE};
 
-fn fuzzy_search(search: &str) -> Option<String> {
-    let stations = stations();
-    stations
+/// Leading words users may add or omit, e.g. "Fiume Savio" for "Savio"
+const HYDRONYM_PREFIXES: [&str; 4] = ["fiume", "torrente", "rio", "canale"];
+/// Edit distances from this value on are not considered a match
+const MAX_MATCH_DISTANCE: usize = 4;
+
+/// How a chat's station queries are matched against the known names
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SearchMode {
+    /// Only a case-insensitive match of the full name
+    Exact,
+    /// Closest name by edit distance
+    Fuzzy,
+}
+
+impl SearchMode {
+    pub(crate) fn from_attr(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "esatta" => Some(Self::Exact),
+            "fuzzy" => Some(Self::Fuzzy),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_attr(&self) -> &'static str {
+        match self {
+            Self::Exact => "esatta",
+            Self::Fuzzy => "fuzzy",
+        }
+    }
+}
+
+pub(crate) fn fuzzy_search(search: &str) -> Option<String> {
+    fuzzy_match(search, &stations())
+}
+
+fn exact_match(search: &str, candidates: &[String]) -> Option<String> {
+    let search = search.trim();
+    candidates
         .iter()
-        .map(|s: &String| {
+        .find(|candidate| candidate.eq_ignore_ascii_case(search))
+        .cloned()
+}
+
+pub(crate) fn resolve_station_name(search: &str, mode: SearchMode) -> Option<String> {
+    match mode {
+        SearchMode::Exact => exact_match(search, &stations()),
+        SearchMode::Fuzzy => fuzzy_search(search),
+    }
+}
+
+fn fuzzy_match(search: &str, candidates: &[String]) -> Option<String> {
+    scored_candidates(search, candidates)
+        .into_iter()
+        .next()
+        .filter(|(_, score)| *score < MAX_MATCH_DISTANCE)
+        .map(|(station, _)| station)
+}
+
+/// Every candidate with its edit distance from the normalized query, closest
+/// first; ties keep the candidates' order
+fn scored_candidates(search: &str, candidates: &[String]) -> Vec<(String, usize)> {
+    let search = normalize_name(search);
+    let mut scored: Vec<(String, usize)> = candidates
+        .iter()
+        .map(|s| {
             (
-                s,
-                edit_distance::edit_distance(
-                    &search.to_lowercase(),
-                    &s.replace(" ", "").to_lowercase(),
-                ),
+                s.clone(),
+                edit_distance::edit_distance(&search, &normalize_name(s)),
             )
         })
-        .filter(|(_, score)| *score < 4)
-        .min_by_key(|(_, score)| *score)
-        .map(|(station, _)| station.clone())
+        .collect();
+    scored.sort_by_key(|(_, score)| *score);
+    scored
 }
 
-pub async fn get_station(
+/// Ranked list of the closest stations to `search`, marking the one
+/// `fuzzy_search` would pick
+pub(crate) fn match_explanation(search: &str, limit: usize) -> String {
+    let scored = scored_candidates(search, &stations());
+    let mut lines = vec![format!(
+        "Query normalizzata: \"{}\" (distanza massima accettata: {})",
+        normalize_name(search),
+        MAX_MATCH_DISTANCE - 1
+    )];
+    lines.extend(
+        scored
+            .iter()
+            .take(limit)
+            .enumerate()
+            .map(|(i, (station, score))| {
+                let picked = if i == 0 && *score < MAX_MATCH_DISTANCE {
+                    " ✅"
+                } else {
+                    ""
+                };
+                format!("{}. {} (distanza {}){}", i + 1, station, score, picked)
+            }),
+    );
+    lines.join("\n")
+}
+
+/// Lowercased name without spaces and without a leading hydronym word
+fn normalize_name(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    let name = match name.split_once(' ') {
+        Some((first, rest)) if HYDRONYM_PREFIXES.contains(&first) && !rest.trim().is_empty() => {
+            rest
+        }
+        _ => name.as_str(),
+    };
+    name.replace(" ", "")
+}
+
+pub(crate) async fn get_station_with_mode(
     client: &DynamoDbClient,
     station_name: String,
     table_name: &str,
+    mode: SearchMode,
 ) -> Result<Option<Stazione>> {
-    if let Some(closest_match) = fuzzy_search(&station_name) {
+    if let Some(closest_match) = resolve_station_name(&station_name, mode) {
         let result = client
             .get_item()
             .table_name(table_name)
@@ -36,31 +136,7 @@ pub async fn get_station(
             .await?;
 
         match result.item {
-            Some(item) => {
-                let idstazione = parse_string_field(&item, "idstazione")?;
-                let timestamp = parse_number_field::<i64>(&item, "timestamp")?;
-                let lon = parse_string_field(&item, "lon")?;
-                let lat = parse_string_field(&item, "lat")?;
-                let ordinamento = parse_number_field::<i32>(&item, "ordinamento")?;
-                let nomestaz = parse_string_field(&item, "nomestaz")?;
-                let soglia1 = parse_number_field::<f64>(&item, "soglia1")?;
-                let soglia2 = parse_number_field::<f64>(&item, "soglia2")?;
-                let soglia3 = parse_number_field::<f64>(&item, "soglia3")?;
-                let value = parse_optional_number_field(&item, "value")?.unwrap_or(UNKNOWN_VALUE);
-
-                Ok(Some(Stazione {
-                    timestamp,
-                    idstazione,
-                    ordinamento,
-                    nomestaz,
-                    lon,
-                    lat,
-                    soglia1,
-                    soglia2,
-                    soglia3,
-                    value,
-                }))
-            }
+            Some(item) => Ok(Some(Stazione::from_item(&item)?)),
             None => Err(anyhow!("Station '{}' not found", closest_match)),
         }
     } else {
@@ -68,6 +144,136 @@ pub async fn get_station(
     }
 }
 
+pub async fn list_stations_with_values(
+    client: &DynamoDbClient,
+    table_name: &str,
+) -> Result<Vec<Stazione>> {
+    let items = paginate(|start_key| async move {
+        let result = client
+            .scan()
+            .table_name(table_name)
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        Ok((result.items.unwrap_or_default(), result.last_evaluated_key))
+    })
+    .await?;
+
+    let mut stations = Vec::with_capacity(items.len());
+    for item in &items {
+        match Stazione::from_item(item) {
+            Ok(station) => stations.push(station),
+            Err(e) => warn!(error = %e, "Skipping unparsable station item"),
+        }
+    }
+    Ok(stations)
+}
+
+pub async fn list_stations_ordered(
+    client: &DynamoDbClient,
+    table_name: &str,
+) -> Result<Vec<String>> {
+    let items = paginate(|start_key| async move {
+        let result = client
+            .scan()
+            .table_name(table_name)
+            .projection_expression("nomestaz, ordinamento")
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        Ok((result.items.unwrap_or_default(), result.last_evaluated_key))
+    })
+    .await?;
+
+    let stations = items
+        .iter()
+        .map(|item| {
+            Ok((
+                parse_string_field(item, "nomestaz")?,
+                parse_number_field::<i32>(item, "ordinamento")?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(sort_stations(stations, StationsSort::Ordinamento))
+}
+
+type Item = HashMap<String, AttributeValue>;
+
+/// Collects the items of every page of a scan or query: `fetch_page` gets the
+/// exclusive start key and returns the page items with `last_evaluated_key`
+async fn paginate<F, Fut>(mut fetch_page: F) -> Result<Vec<Item>>
+where
+    F: FnMut(Option<Item>) -> Fut,
+    Fut: Future<Output = Result<(Vec<Item>, Option<Item>)>>,
+{
+    let mut items = Vec::new();
+    let mut start_key = None;
+    loop {
+        let (page, last_evaluated_key) = fetch_page(start_key).await?;
+        items.extend(page);
+        match last_evaluated_key {
+            Some(key) if !key.is_empty() => start_key = Some(key),
+            _ => break,
+        }
+    }
+    Ok(items)
+}
+
+/// `ItemCount` is refreshed by DynamoDB roughly every six hours, but reading
+/// it is free compared to scanning the whole table
+pub async fn describe_table_item_count(client: &DynamoDbClient, table_name: &str) -> Result<i64> {
+    let result = client
+        .describe_table()
+        .table_name(table_name)
+        .send()
+        .await?;
+    Ok(result
+        .table()
+        .and_then(|table| table.item_count())
+        .unwrap_or_default())
+}
+
+/// Deletes a station record so the next fetch recreates it from scratch.
+/// `false` when there was no record with that name
+pub(crate) async fn delete_station_record(
+    client: &DynamoDbClient,
+    table_name: &str,
+    nomestaz: &str,
+) -> Result<bool> {
+    let result = client
+        .delete_item()
+        .table_name(table_name)
+        .key("nomestaz", AttributeValue::S(nomestaz.to_string()))
+        .return_values(ReturnValue::AllOld)
+        .send()
+        .await?;
+    Ok(result.attributes.is_some_and(|item| !item.is_empty()))
+}
+
+impl Stazione {
+    /// Builds a station from a DynamoDB item, tolerating items written before
+    /// a field was introduced: only `nomestaz` and `timestamp` are required
+    pub(crate) fn from_item(item: &HashMap<String, AttributeValue>) -> Result<Self> {
+        Ok(Stazione {
+            timestamp: parse_number_field::<i64>(item, "timestamp")?,
+            idstazione: parse_optional_string_field(item, "idstazione")?.unwrap_or_default(),
+            ordinamento: parse_optional_number_field::<i32>(item, "ordinamento")?.unwrap_or(0),
+            nomestaz: parse_string_field(item, "nomestaz")?,
+            lon: parse_optional_string_field(item, "lon")?.unwrap_or_default(),
+            lat: parse_optional_string_field(item, "lat")?.unwrap_or_default(),
+            soglia1: parse_optional_number_field(item, "soglia1")?.unwrap_or(UNKNOWN_VALUE),
+            soglia2: parse_optional_number_field(item, "soglia2")?.unwrap_or(UNKNOWN_VALUE),
+            soglia3: parse_optional_number_field(item, "soglia3")?.unwrap_or(UNKNOWN_VALUE),
+            value: parse_optional_number_field(item, "value")?.unwrap_or(UNKNOWN_VALUE),
+            previous_value: parse_optional_number_field(item, "previous_value")?,
+            previous_timestamp: parse_optional_number_field(item, "previous_timestamp")?,
+            thresholds_valid: parse_optional_bool_field(item, "thresholds_valid")?.unwrap_or(true),
+            unit: parse_optional_string_field(item, "unit")?
+                .unwrap_or_else(|| DEFAULT_UNIT.to_string()),
+        })
+    }
+}
+
 fn parse_string_field(item: &HashMap<String, AttributeValue>, field: &str) -> Result<String> {
     match item.get(field) {
         Some(AttributeValue::S(s)) => Ok(s.clone()),
@@ -76,6 +282,16 @@ fn parse_string_field(item: &HashMap<String, AttributeValue>, field: &str) -> Re
     }
 }
 
+fn parse_optional_string_field(
+    item: &HashMap<String, AttributeValue>,
+    field: &str,
+) -> Result<Option<String>> {
+    match item.get(field) {
+        None | Some(AttributeValue::Null(_)) => Ok(None),
+        Some(_) => parse_string_field(item, field).map(Some),
+    }
+}
+
 fn parse_number_field<T: std::str::FromStr>(
     item: &HashMap<String, AttributeValue>,
     field: &str,
@@ -134,6 +350,29 @@ where
                 ))
             }
         }
+        None | Some(AttributeValue::Null(_)) => Ok(None),
+        _ => Err(anyhow!("Invalid type for '{}' field", field)),
+    }
+}
+
+fn parse_optional_bool_field(
+    item: &HashMap<String, AttributeValue>,
+    field: &str,
+) -> Result<Option<bool>> {
+    match item.get(field) {
+        Some(AttributeValue::Bool(b)) => Ok(Some(*b)),
+        Some(AttributeValue::N(s)) | Some(AttributeValue::S(s)) => {
+            match s.trim().to_lowercase().as_str() {
+                "1" | "true" => Ok(Some(true)),
+                "0" | "false" => Ok(Some(false)),
+                _ => Err(anyhow!(
+                    "Failed to parse '{}' field with value '{}' as boolean",
+                    field,
+                    s
+                )),
+            }
+        }
+        None => Ok(None),
         _ => Err(anyhow!("Invalid type for '{}' field", field)),
     }
 }
@@ -141,6 +380,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::mock_dynamodb_client;
 
     #[test]
     fn fuzzy_search_cesena_yields_cesena_station() {
@@ -174,6 +414,173 @@ mod tests {
         assert_eq!(fuzzy_search(&message), expected);
     }
 
+    #[test]
+    fn fuzzy_match_ignores_hydronym_prefix_in_candidates() {
+        let candidates = vec!["Fiume Savio".to_string(), "Torrente Idice".to_string()];
+        assert_eq!(
+            fuzzy_match("savio", &candidates),
+            Some("Fiume Savio".to_string())
+        );
+        assert_eq!(
+            fuzzy_match("Idice", &candidates),
+            Some("Torrente Idice".to_string())
+        );
+    }
+
+    #[test]
+    fn fuzzy_search_ignores_hydronym_prefix_in_query() {
+        assert_eq!(fuzzy_search("Fiume Cesena"), Some("Cesena".to_string()));
+        assert_eq!(fuzzy_search("rio S. Carlo"), Some("S. Carlo".to_string()));
+    }
+
+    #[test]
+    fn scored_candidates_are_sorted_by_ascending_distance() {
+        let candidates = vec![
+            "Borello".to_string(),
+            "Cesenatico".to_string(),
+            "Cesena".to_string(),
+        ];
+        let scored = scored_candidates("cesena", &candidates);
+        let names: Vec<&str> = scored.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(names, vec!["Cesena", "Cesenatico", "Borello"]);
+        assert!(scored.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+    }
+
+    #[test]
+    fn match_explanation_marks_the_picked_station() {
+        let explanation = match_explanation("Cesena", 3);
+        let lines: Vec<&str> = explanation.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1], "1. Cesena (distanza 0) ✅");
+        assert!(!lines[2].ends_with('✅'));
+    }
+
+    #[test]
+    fn match_explanation_without_match_marks_nothing() {
+        assert!(!match_explanation("nonexisting", 5).contains('✅'));
+    }
+
+    #[test]
+    fn exact_mode_does_not_fall_back_to_fuzzy() {
+        assert_eq!(
+            resolve_station_name("ecsena", SearchMode::Fuzzy),
+            Some("Cesena".to_string())
+        );
+        assert_eq!(resolve_station_name("ecsena", SearchMode::Exact), None);
+    }
+
+    #[test]
+    fn exact_mode_ignores_case_and_surrounding_spaces() {
+        assert_eq!(
+            resolve_station_name(" s. carlo ", SearchMode::Exact),
+            Some("S. Carlo".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn get_station_with_exact_mode_yields_not_found_without_request() {
+        let (client, request) = mock_dynamodb_client("{}");
+
+        let result =
+            get_station_with_mode(&client, "ecsena".to_string(), "Stazioni", SearchMode::Exact)
+                .await;
+
+        assert!(result.is_err());
+        request.expect_no_request();
+    }
+
+    #[test]
+    fn search_mode_attr_roundtrip() {
+        for mode in [SearchMode::Exact, SearchMode::Fuzzy] {
+            assert_eq!(SearchMode::from_attr(mode.as_attr()), Some(mode));
+        }
+        assert_eq!(SearchMode::from_attr(" Esatta "), Some(SearchMode::Exact));
+        assert_eq!(SearchMode::from_attr("other"), None);
+    }
+
+    #[test]
+    fn normalize_name_keeps_a_lone_hydronym() {
+        assert_eq!(normalize_name("Canale"), "canale");
+        assert_eq!(normalize_name("Casalecchio canale"), "casalecchiocanale");
+    }
+
+    fn page_item(name: &str) -> Item {
+        HashMap::from([("nomestaz".to_string(), AttributeValue::S(name.to_string()))])
+    }
+
+    #[tokio::test]
+    async fn paginate_accumulates_every_page() {
+        let pages = vec![
+            (
+                vec![page_item("Cesena"), page_item("Lugo")],
+                Some(page_item("Lugo")),
+            ),
+            (vec![page_item("S. Carlo")], Some(page_item("S. Carlo"))),
+            (vec![page_item("Borello")], None),
+        ];
+        let mut start_keys = Vec::new();
+        let mut pages = pages.into_iter();
+
+        let items = paginate(|start_key| {
+            start_keys.push(start_key);
+            let page = pages.next().unwrap();
+            async move { Ok(page) }
+        })
+        .await
+        .unwrap();
+
+        let names: Vec<String> = items
+            .iter()
+            .map(|item| parse_string_field(item, "nomestaz").unwrap())
+            .collect();
+        assert_eq!(names, vec!["Cesena", "Lugo", "S. Carlo", "Borello"]);
+        assert_eq!(
+            start_keys,
+            vec![None, Some(page_item("Lugo")), Some(page_item("S. Carlo"))]
+        );
+    }
+
+    #[tokio::test]
+    async fn paginate_stops_on_empty_last_evaluated_key() {
+        let mut calls = 0;
+        let items = paginate(|_| {
+            calls += 1;
+            async { Ok((vec![page_item("Cesena")], Some(HashMap::new()))) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn list_stations_with_values_stops_on_empty_last_evaluated_key() {
+        // The mock answers a single request: another page would fail the test
+        let (client, _request) = mock_dynamodb_client(
+            r#"{
+                "Items": [{
+                    "nomestaz": {"S": "Cesena"},
+                    "timestamp": {"N": "1729454542656"},
+                    "value": {"N": "2.2"}
+                }],
+                "Count": 1,
+                "ScannedCount": 1,
+                "LastEvaluatedKey": {}
+            }"#,
+        );
+        let stations = list_stations_with_values(&client, "Stazioni")
+            .await
+            .unwrap();
+        assert_eq!(stations.len(), 1);
+        assert_eq!(stations[0].nomestaz, "Cesena");
+    }
+
+    #[tokio::test]
+    async fn paginate_propagates_page_errors() {
+        let result = paginate(|_| async { Err(anyhow!("throttled")) }).await;
+        assert!(result.is_err());
+    }
+
     #[test]
     fn parse_string_field_yields_correct_value() {
         let expected = "this is a string".to_string();
@@ -181,6 +588,115 @@ mod tests {
         assert_eq!(parse_string_field(&item, "field").unwrap(), expected);
     }
 
+    fn legacy_item() -> HashMap<String, AttributeValue> {
+        HashMap::from([
+            (
+                "nomestaz".to_string(),
+                AttributeValue::S("Cesena".to_string()),
+            ),
+            (
+                "timestamp".to_string(),
+                AttributeValue::N("1726667100000".to_string()),
+            ),
+        ])
+    }
+
+    fn full_item() -> HashMap<String, AttributeValue> {
+        let mut item = legacy_item();
+        item.extend([
+            (
+                "idstazione".to_string(),
+                AttributeValue::S("/id/".to_string()),
+            ),
+            (
+                "ordinamento".to_string(),
+                AttributeValue::N("3".to_string()),
+            ),
+            ("lon".to_string(), AttributeValue::S("12.24".to_string())),
+            ("lat".to_string(), AttributeValue::S("44.13".to_string())),
+            ("soglia1".to_string(), AttributeValue::N("1.0".to_string())),
+            ("soglia2".to_string(), AttributeValue::N("2.0".to_string())),
+            ("soglia3".to_string(), AttributeValue::N("3.0".to_string())),
+            ("value".to_string(), AttributeValue::N("1.5".to_string())),
+            (
+                "previous_value".to_string(),
+                AttributeValue::N("1.25".to_string()),
+            ),
+            (
+                "previous_timestamp".to_string(),
+                AttributeValue::N("1726663500000".to_string()),
+            ),
+        ]);
+        item
+    }
+
+    #[test]
+    fn from_item_parses_full_item() {
+        let station = Stazione::from_item(&full_item()).unwrap();
+        assert_eq!(station.nomestaz, "Cesena");
+        assert_eq!(station.timestamp, 1726667100000);
+        assert_eq!(station.idstazione, "/id/");
+        assert_eq!(station.ordinamento, 3);
+        assert_eq!(station.lon, "12.24");
+        assert_eq!(station.lat, "44.13");
+        assert_eq!(
+            (station.soglia1, station.soglia2, station.soglia3),
+            (1.0, 2.0, 3.0)
+        );
+        assert_eq!(station.value, 1.5);
+        assert_eq!(station.previous_value, Some(1.25));
+        assert_eq!(station.previous_timestamp, Some(1726663500000));
+    }
+
+    #[test]
+    fn from_item_accepts_legacy_item_without_optional_fields() {
+        let station = Stazione::from_item(&legacy_item()).unwrap();
+        assert_eq!(station.nomestaz, "Cesena");
+        assert_eq!(station.ordinamento, 0);
+        assert_eq!(station.idstazione, "");
+        assert_eq!(station.lon, "");
+        assert_eq!(station.lat, "");
+        assert_eq!(station.soglia1, UNKNOWN_VALUE);
+        assert_eq!(station.soglia3, UNKNOWN_VALUE);
+        assert_eq!(station.value, UNKNOWN_VALUE);
+        assert_eq!(station.previous_value, None);
+        assert!(station.thresholds_valid);
+        assert_eq!(station.unit, "m");
+    }
+
+    #[test]
+    fn from_item_reads_unit() {
+        let mut item = full_item();
+        item.insert("unit".to_string(), AttributeValue::S("mm".to_string()));
+        assert_eq!(Stazione::from_item(&item).unwrap().unit, "mm");
+    }
+
+    #[test]
+    fn from_item_reads_thresholds_valid_flag() {
+        let mut item = full_item();
+        item.insert("thresholds_valid".to_string(), AttributeValue::Bool(false));
+        assert!(!Stazione::from_item(&item).unwrap().thresholds_valid);
+    }
+
+    #[test]
+    fn from_item_requires_name_and_timestamp() {
+        for field in ["nomestaz", "timestamp"] {
+            let mut item = full_item();
+            item.remove(field);
+            assert!(Stazione::from_item(&item).is_err());
+        }
+    }
+
+    #[test]
+    fn from_item_rejects_corrupted_field() {
+        let mut item = full_item();
+        item.insert(
+            "soglia2".to_string(),
+            AttributeValue::S("not a number".to_string()),
+        );
+        assert!(Stazione::from_item(&item).is_err());
+    }
+
     #[test]
     fn parse_optional_number_field_yields_correct_value() {
         let expected = 4;
@@ -190,4 +706,98 @@ mod tests {
             Some(expected)
         );
     }
+
+    #[test]
+    fn parse_optional_bool_field_yields_bool_value() {
+        let item = HashMap::from([("field".to_string(), AttributeValue::Bool(true))]);
+        assert_eq!(
+            parse_optional_bool_field(&item, "field").unwrap(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn parse_optional_bool_field_accepts_string_true() {
+        let item = HashMap::from([("field".to_string(), AttributeValue::S("true".to_string()))]);
+        assert_eq!(
+            parse_optional_bool_field(&item, "field").unwrap(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn parse_optional_bool_field_accepts_numeric_flags() {
+        let item = HashMap::from([
+            ("on".to_string(), AttributeValue::N("1".to_string())),
+            ("off".to_string(), AttributeValue::N("0".to_string())),
+        ]);
+        assert_eq!(parse_optional_bool_field(&item, "on").unwrap(), Some(true));
+        assert_eq!(
+            parse_optional_bool_field(&item, "off").unwrap(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn parse_optional_bool_field_rejects_garbage() {
+        let item = HashMap::from([("field".to_string(), AttributeValue::S("maybe".to_string()))]);
+        assert!(parse_optional_bool_field(&item, "field").is_err());
+    }
+
+    #[test]
+    fn parse_optional_bool_field_missing_yields_none() {
+        let item = HashMap::new();
+        assert_eq!(parse_optional_bool_field(&item, "field").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn describe_table_item_count_requests_the_given_table() {
+        let (client, request) =
+            mock_dynamodb_client(r#"{"Table":{"TableName":"Stazioni","ItemCount":290}}"#);
+
+        let count = describe_table_item_count(&client, "Stazioni")
+            .await
+            .unwrap();
+
+        let request = request.expect_request();
+        assert_eq!(
+            request.headers().get("x-amz-target"),
+            Some("DynamoDB_20120810.DescribeTable")
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["TableName"], "Stazioni");
+        assert_eq!(count, 290);
+    }
+
+    #[tokio::test]
+    async fn delete_station_record_deletes_by_name() {
+        let (client, request) = mock_dynamodb_client(
+            r#"{"Attributes":{"nomestaz":{"S":"Cesena"},"timestamp":{"N":"1729454542656"}}}"#,
+        );
+
+        assert!(delete_station_record(&client, "Stazioni", "Cesena")
+            .await
+            .unwrap());
+
+        let request = request.expect_request();
+        assert_eq!(
+            request.headers().get("x-amz-target"),
+            Some("DynamoDB_20120810.DeleteItem")
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["TableName"], "Stazioni");
+        assert_eq!(body["Key"]["nomestaz"]["S"], "Cesena");
+        assert_eq!(body["ReturnValues"], "ALL_OLD");
+    }
+
+    #[tokio::test]
+    async fn delete_station_record_without_record() {
+        let (client, _request) = mock_dynamodb_client("{}");
+
+        assert!(!delete_station_record(&client, "Stazioni", "Cesena")
+            .await
+            .unwrap());
+    }
 }
@@ -0,0 +1,253 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{sync::OnceLock, time::Duration};
+
+use super::Stazione;
+
+// Same defaults and overrides as the fetcher's `ApiConfig`: Pulumi passes the
+// same `ER_*` variables to both Lambdas
+const API_BASE: &str = "https://allertameteo.regione.emilia-romagna.it/o/api/allerta";
+const SENSOR_VARIABLE: &str = "254,0,0/1,-,-,-/B13215";
+/// Well below the bot Lambda timeout so a slow portal doesn't stall the update
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const MAX_HORIZON_HOURS: i64 = 48;
+pub(crate) const SERIES_UNAVAILABLE: &str = "serie non disponibile";
+
+/// Portal parameters, overridable with `ER_API_BASE` and `ER_SENSOR_VARIABLE`
+#[derive(Debug, Clone, PartialEq)]
+struct PortalConfig {
+    api_base: String,
+    sensor_variable: String,
+}
+
+impl PortalConfig {
+    fn from_env() -> Self {
+        Self::from_values(
+            std::env::var("ER_API_BASE").ok(),
+            std::env::var("ER_SENSOR_VARIABLE").ok(),
+        )
+    }
+
+    fn from_values(api_base: Option<String>, sensor_variable: Option<String>) -> Self {
+        Self {
+            api_base: api_base
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| API_BASE.to_string()),
+            sensor_variable: sensor_variable
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| SENSOR_VARIABLE.to_string()),
+        }
+    }
+
+    /// `None` for legacy records stored without `idstazione`
+    fn series_url(&self, idstazione: &str) -> Option<String> {
+        (!idstazione.trim().is_empty()).then(|| {
+            format!(
+                "{}/get-time-series/?stazione={}&variabile={}",
+                self.api_base, idstazione, self.sensor_variable
+            )
+        })
+    }
+}
+
+/// Shared across the invocations of a warm Lambda
+fn http_client() -> Result<&'static reqwest::Client> {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+#[derive(Deserialize)]
+struct SeriesPoint {
+    t: Value,
+    v: Option<f64>,
+}
+
+/// Readings of the last days of the station as `(timestamp ms, value)`;
+/// `None` when the station has no `idstazione` to ask the portal for
+pub(crate) async fn fetch_series(station: &Stazione) -> Result<Option<Vec<(i64, f64)>>> {
+    let Some(url) = PortalConfig::from_env().series_url(&station.idstazione) else {
+        return Ok(None);
+    };
+    let points: Vec<SeriesPoint> = http_client()?
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(Some(
+        points
+            .into_iter()
+            .filter_map(|p| Some((parse_timestamp(&p.t)?, p.v?)))
+            .collect(),
+    ))
+}
+
+/// The API sends timestamps either as numbers or as strings
+fn parse_timestamp(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The station with its value replaced by the latest reading of the series,
+/// unchanged when the series has nothing newer than the stored reading
+pub(crate) fn with_latest_reading(mut station: Stazione, points: &[(i64, f64)]) -> Stazione {
+    if let Some((timestamp, value)) = points.iter().max_by_key(|(t, _)| *t) {
+        if *timestamp >= station.timestamp {
+            station.previous_value = Some(station.value);
+            station.previous_timestamp = Some(station.timestamp);
+            station.timestamp = *timestamp;
+            station.value = *value;
+        }
+    }
+    station
+}
+
+/// Min and max value of the readings in the `hours` before `until` (ms)
+pub(crate) fn min_max(points: &[(i64, f64)], until: i64, hours: i64) -> Option<(f64, f64)> {
+    let since = until - hours * 3_600_000;
+    points
+        .iter()
+        .filter(|(t, _)| (since..=until).contains(t))
+        .fold(None, |range, (_, v)| match range {
+            None => Some((*v, *v)),
+            Some((min, max)) => Some((f64::min(min, *v), f64::max(max, *v))),
+        })
+}
+
+/// Min and max of `points` over the `hours` before the station's reading
+pub(crate) fn range_message(station: &Stazione, points: &[(i64, f64)], hours: i64) -> String {
+    match min_max(points, station.timestamp, hours) {
+        Some((min, max)) => format!(
+            "Ultime {}h: minimo {:.2}{}, massimo {:.2}{}",
+            hours, min, station.unit, max, station.unit
+        ),
+        None => format!("Storico delle ultime {}h non disponibile", hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3_600_000;
+
+    fn station(timestamp: i64, value: f64) -> Stazione {
+        let item = std::collections::HashMap::from([
+            (
+                "nomestaz".to_string(),
+                aws_sdk_dynamodb::types::AttributeValue::S("Cesena".to_string()),
+            ),
+            (
+                "timestamp".to_string(),
+                aws_sdk_dynamodb::types::AttributeValue::N(timestamp.to_string()),
+            ),
+            (
+                "value".to_string(),
+                aws_sdk_dynamodb::types::AttributeValue::N(value.to_string()),
+            ),
+        ]);
+        Stazione::from_item(&item).unwrap()
+    }
+
+    #[test]
+    fn with_latest_reading_uses_the_newest_point() {
+        let updated = with_latest_reading(
+            station(HOUR, 1.0),
+            &[(2 * HOUR, 1.4), (3 * HOUR, 1.6), (HOUR, 1.0)],
+        );
+        assert_eq!((updated.timestamp, updated.value), (3 * HOUR, 1.6));
+        assert_eq!(updated.previous_value, Some(1.0));
+        assert_eq!(updated.previous_timestamp, Some(HOUR));
+    }
+
+    #[test]
+    fn with_latest_reading_keeps_newer_stored_value() {
+        let updated = with_latest_reading(station(3 * HOUR, 1.6), &[(HOUR, 1.0)]);
+        assert_eq!((updated.timestamp, updated.value), (3 * HOUR, 1.6));
+        let updated = with_latest_reading(station(3 * HOUR, 1.6), &[]);
+        assert_eq!(updated.previous_value, None);
+    }
+
+    #[test]
+    fn min_max_considers_only_the_horizon() {
+        let points = vec![(0, 5.0), (HOUR, 1.5), (2 * HOUR, 2.5), (3 * HOUR, 2.0)];
+        assert_eq!(min_max(&points, 3 * HOUR, 2), Some((1.5, 2.5)));
+        assert_eq!(min_max(&points, 3 * HOUR, 3), Some((1.5, 5.0)));
+    }
+
+    #[test]
+    fn range_message_formats_min_and_max() {
+        let station = station(3 * HOUR, 1.6);
+        let points = [(HOUR, 0.8), (2 * HOUR, 1.4), (3 * HOUR, 1.6)];
+        assert_eq!(
+            range_message(&station, &points, 1),
+            "Ultime 1h: minimo 1.40m, massimo 1.60m"
+        );
+        assert_eq!(
+            range_message(&station, &[], 2),
+            "Storico delle ultime 2h non disponibile"
+        );
+    }
+
+    #[test]
+    fn min_max_without_readings() {
+        assert_eq!(min_max(&[(0, 1.0)], 10 * HOUR, 6), None);
+        assert_eq!(min_max(&[], 10 * HOUR, 6), None);
+    }
+
+    #[test]
+    fn portal_config_defaults_and_overrides() {
+        assert_eq!(
+            PortalConfig::from_values(None, Some(" ".to_string())),
+            PortalConfig {
+                api_base: API_BASE.to_string(),
+                sensor_variable: SENSOR_VARIABLE.to_string(),
+            }
+        );
+        let config = PortalConfig::from_values(
+            Some("https://example.org/api".to_string()),
+            Some("1,2,3".to_string()),
+        );
+        assert_eq!(
+            config.series_url("/id/").unwrap(),
+            "https://example.org/api/get-time-series/?stazione=/id/&variabile=1,2,3"
+        );
+    }
+
+    #[test]
+    fn series_url_requires_idstazione() {
+        let config = PortalConfig::from_values(None, None);
+        assert_eq!(config.series_url(""), None);
+        assert_eq!(config.series_url("  "), None);
+    }
+
+    #[tokio::test]
+    async fn fetch_series_without_idstazione_is_unavailable() {
+        // Legacy record: no request is made, so this runs offline
+        assert_eq!(fetch_series(&station(HOUR, 1.0)).await.unwrap(), None);
+    }
+
+    #[test]
+    fn parse_timestamp_accepts_numbers_and_strings() {
+        assert_eq!(
+            parse_timestamp(&Value::from(1729454542656_i64)),
+            Some(1729454542656)
+        );
+        assert_eq!(
+            parse_timestamp(&Value::from("1729454542656")),
+            Some(1729454542656)
+        );
+        assert_eq!(parse_timestamp(&Value::Null), None);
+    }
+}
@@ -0,0 +1,109 @@
+use aws_sdk_dynamodb::{
+    config::{BehaviorVersion, Credentials, Region},
+    Client as DynamoDbClient,
+};
+use aws_smithy_runtime::client::http::test_util::{capture_request, CaptureRequestReceiver};
+use aws_smithy_types::body::SdkBody;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// DynamoDB client answering a single request with `response_body`, the
+/// receiver exposes the request that was sent
+pub(crate) fn mock_dynamodb_client(
+    response_body: &str,
+) -> (DynamoDbClient, CaptureRequestReceiver) {
+    mock_dynamodb_client_with_status(200, response_body)
+}
+
+pub(crate) fn mock_dynamodb_client_with_status(
+    status: u16,
+    response_body: &str,
+) -> (DynamoDbClient, CaptureRequestReceiver) {
+    let (http_client, request) = capture_request(Some(
+        http::Response::builder()
+            .status(status)
+            .body(SdkBody::from(response_body.to_string()))
+            .unwrap(),
+    ));
+    let config = aws_sdk_dynamodb::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .region(Region::new("eu-west-1"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .http_client(http_client)
+        .build();
+    (DynamoDbClient::from_conf(config), request)
+}
+
+pub(crate) fn request_json(request: CaptureRequestReceiver) -> serde_json::Value {
+    serde_json::from_slice(request.expect_request().body().bytes().unwrap()).unwrap()
+}
+
+/// Telegram Bot API stand-in on a local port: `getMe` answers with the bot
+/// and every other method with a sent message. The receiver yields the
+/// method and JSON body of each request.
+pub(crate) fn mock_telegram_server() -> (reqwest::Url, Receiver<(String, serde_json::Value)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+            let method = path.rsplit('/').next().unwrap_or_default().to_lowercase();
+            let result = if method == "getme" {
+                serde_json::json!({
+                    "id": 1,
+                    "is_bot": true,
+                    "first_name": "erfiume",
+                    "username": "erfiume_bot",
+                    "can_join_groups": true,
+                    "can_read_all_group_messages": false,
+                    "supports_inline_queries": false
+                })
+            } else {
+                serde_json::json!({
+                    "message_id": 2,
+                    "date": 1729454542,
+                    "chat": {"id": 1, "type": "private", "first_name": "erfiume"},
+                    "text": "ok"
+                })
+            };
+            let response = serde_json::json!({"ok": true, "result": result}).to_string();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+            let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+            let _ = sender.send((method, body));
+        }
+    });
+    (url, receiver)
+}
@@ -8,15 +8,63 @@ use futures::StreamExt;
 use lambda_runtime::{service_fn, Error as LambdaError, LambdaEvent};
 use serde::de::{self, Visitor};
 use serde::{Deserialize, Deserializer, Serialize};
-use serde_json::{json, Value};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt;
-use std::time::Duration;
-use tracing::{error, info, instrument};
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, instrument, warn};
 use tracing_subscriber::EnvFilter;
 
 type BoxError = Box<dyn StdError + Send + Sync>;
 
+const API_BASE: &str = "https://allertameteo.regione.emilia-romagna.it/o/api/allerta";
+const SENSOR_VARIABLE: &str = "254,0,0/1,-,-,-/B13215";
+const LATEST_TIME_SEED: i64 = 1726667100000;
+const FETCH_STATUS_TABLE: &str = "FetchStatus";
+const REGION_KEY: &str = "emilia_romagna";
+/// The sensor variable reads hydrometric levels in meters
+const DEFAULT_UNIT: &str = "m";
+
+/// Portal parameters, overridable with `ER_API_BASE`, `ER_SENSOR_VARIABLE`
+/// and `ER_LATEST_TIME_SEED` if the portal changes its contract; the bot
+/// reads the first two as well for the time series
+#[derive(Debug, Clone, PartialEq)]
+struct ApiConfig {
+    api_base: String,
+    sensor_variable: String,
+    latest_time_seed: i64,
+}
+
+impl ApiConfig {
+    fn from_env() -> Self {
+        Self::from_values(
+            std::env::var("ER_API_BASE").ok(),
+            std::env::var("ER_SENSOR_VARIABLE").ok(),
+            std::env::var("ER_LATEST_TIME_SEED").ok(),
+        )
+    }
+
+    fn from_values(
+        api_base: Option<String>,
+        sensor_variable: Option<String>,
+        latest_time_seed: Option<String>,
+    ) -> Self {
+        Self {
+            api_base: api_base
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| API_BASE.to_string()),
+            sensor_variable: sensor_variable
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or_else(|| SENSOR_VARIABLE.to_string()),
+            latest_time_seed: latest_time_seed
+                .and_then(|seed| seed.trim().parse().ok())
+                .unwrap_or(LATEST_TIME_SEED),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
 enum Entry {
@@ -28,11 +76,11 @@ enum Entry {
         ordinamento: i32,
         nomestaz: String,
         lon: String,
-        soglia1: f32,
+        soglia1: f64,
         value: Option<String>,
-        soglia2: f32,
+        soglia2: f64,
         lat: String,
-        soglia3: f32,
+        soglia3: f64,
         timestap: Option<u64>,
     },
 }
@@ -45,17 +93,48 @@ struct Station {
     nomestaz: String,
     lon: String,
     lat: String,
-    soglia1: f32,
-    soglia2: f32,
-    soglia3: f32,
-    value: Option<f32>,
+    soglia1: f64,
+    soglia2: f64,
+    soglia3: f64,
+    value: Option<f64>,
+    #[serde(default = "default_unit")]
+    unit: String,
+}
+
+fn default_unit() -> String {
+    DEFAULT_UNIT.to_string()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FetchResult {
+    message: String,
+    stations_processed: usize,
+    stations_updated: usize,
+    status_code: u16,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SeedResult {
+    message: String,
+    stations_seeded: usize,
+    stations_failed: usize,
+    status_code: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Action {
+    /// Restore station records from a backup without scraping the portal
+    Seed { items: Vec<Value> },
 }
 
 #[derive(Debug, Deserialize)]
 struct StationData {
     #[serde(deserialize_with = "deserialize_timestamp")]
     t: u64,
-    v: Option<f32>,
+    v: Option<f64>,
 }
 
 fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<u64, D::Error>
@@ -86,11 +165,12 @@ where
     deserializer.deserialize_any(TimestampVisitor)
 }
 
-async fn fetch_latest_time(client: &reqwest::Client) -> Result<i64, BoxError> {
-    let response = client
-        .get("https://allertameteo.regione.emilia-romagna.it/o/api/allerta/get-sensor-values-no-time?variabile=254,0,0/1,-,-,-/B13215&time=1726667100000")
-        .send()
-        .await?;
+async fn fetch_latest_time(client: &reqwest::Client, config: &ApiConfig) -> Result<i64, BoxError> {
+    let url = format!(
+        "{}/get-sensor-values-no-time?variabile={}&time={}",
+        config.api_base, config.sensor_variable, config.latest_time_seed
+    );
+    let response = client.get(&url).send().await?;
 
     response.error_for_status_ref()?;
 
@@ -109,28 +189,39 @@ async fn fetch_latest_time(client: &reqwest::Client) -> Result<i64, BoxError> {
 
 async fn fetch_stations(
     client: &reqwest::Client,
+    config: &ApiConfig,
     timestamp: i64,
 ) -> Result<Vec<Station>, BoxError> {
-    let url = format!("https://allertameteo.regione.emilia-romagna.it/o/api/allerta/get-sensor-values-no-time?variabile=254,0,0/1,-,-,-/B13215&time={}", timestamp);
+    let url = format!(
+        "{}/get-sensor-values-no-time?variabile={}&time={}",
+        config.api_base, config.sensor_variable, timestamp
+    );
     let response = client.get(&url).send().await?;
     response.error_for_status_ref()?;
 
     let entries: Vec<Entry> = response.json().await?;
-    let stations = entries
-        .into_iter()
-        .filter_map(|e| match e {
-            Entry::DataEntry {
-                idstazione,
-                ordinamento,
-                nomestaz,
-                lon,
-                soglia1,
-                value: _,
-                soglia2,
-                lat,
-                soglia3,
-                timestap: _,
-            } => Some(Station {
+    Ok(entries.into_iter().filter_map(station_from_entry).collect())
+}
+
+/// Only the entry's own sensor time is kept: an entry without one gets no
+/// timestamp, so its reading is taken from the time series instead of being
+/// stamped with the time the listing was requested at
+fn station_from_entry(entry: Entry) -> Option<Station> {
+    match entry {
+        Entry::DataEntry {
+            idstazione,
+            ordinamento,
+            nomestaz,
+            lon,
+            soglia1,
+            value,
+            soglia2,
+            lat,
+            soglia3,
+            timestap,
+        } => {
+            let value = value.as_deref().and_then(parse_er_value);
+            Some(Station {
                 idstazione,
                 ordinamento,
                 nomestaz,
@@ -139,20 +230,65 @@ async fn fetch_stations(
                 soglia2,
                 soglia3,
                 lat,
-                timestamp: None,
-                value: None,
-            }),
-            Entry::TimeEntry { .. } => None,
-        })
-        .collect();
+                timestamp: value.and(timestap),
+                value,
+                unit: default_unit(),
+            })
+        }
+        Entry::TimeEntry { .. } => None,
+    }
+}
+
+/// Parses the value string of the stations listing: both `2,20` and `2.20`
+/// are used by the portal, an empty string means no reading
+fn parse_er_value(value: &str) -> Option<f64> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    value
+        .replace(',', ".")
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite())
+}
+
+/// An empty listing for the latest time means the portal no longer honours
+/// it: retry once with the current time, then fail the run so that an empty
+/// portal is not reported as a successful update
+async fn fetch_stations_with_fallback<F, Fut>(
+    fetch: F,
+    latest_timestamp: i64,
+    now: i64,
+) -> Result<Vec<Station>, BoxError>
+where
+    F: Fn(i64) -> Fut,
+    Fut: Future<Output = Result<Vec<Station>, BoxError>>,
+{
+    let stations = fetch(latest_timestamp).await?;
+    if !stations.is_empty() {
+        return Ok(stations);
+    }
+    warn!(
+        latest_timestamp,
+        now, "No stations for the latest time, retrying with the current time"
+    );
+    let stations = fetch(now).await?;
+    if stations.is_empty() {
+        return Err("No stations returned for the latest or the current time".into());
+    }
     Ok(stations)
 }
 
 async fn fetch_station_data(
     client: &reqwest::Client,
+    config: &ApiConfig,
     mut station: Station,
 ) -> Result<Station, BoxError> {
-    let url = format!("https://allertameteo.regione.emilia-romagna.it/o/api/allerta/get-time-series/?stazione={}&variabile=254,0,0/1,-,-,-/B13215", station.idstazione);
+    let url = format!(
+        "{}/get-time-series/?stazione={}&variabile={}",
+        config.api_base, station.idstazione, config.sensor_variable
+    );
     let response = client.get(&url).send().await?;
     response.error_for_status_ref()?;
     let entries: Vec<StationData> = response.json().await?;
@@ -164,6 +300,13 @@ async fn fetch_station_data(
     Ok(station)
 }
 
+/// The portal sometimes swaps thresholds; a zero threshold means the station
+/// has none at that level and is not compared
+fn thresholds_monotonic(soglie: [f64; 3]) -> bool {
+    let known: Vec<f64> = soglie.into_iter().filter(|s| *s > 0.0).collect();
+    known.windows(2).all(|pair| pair[0] <= pair[1])
+}
+
 async fn put_station_into_dynamodb(
     client: &DynamoDbClient,
     station: &Station,
@@ -204,11 +347,32 @@ async fn put_station_into_dynamodb(
         AttributeValue::N(station.soglia3.to_string()),
     );
 
+    expression_attribute_values
+        .insert(":unit".to_string(), AttributeValue::S(station.unit.clone()));
+
+    let thresholds_valid =
+        thresholds_monotonic([station.soglia1, station.soglia2, station.soglia3]);
+    if !thresholds_valid {
+        warn!(
+            soglia1 = station.soglia1,
+            soglia2 = station.soglia2,
+            soglia3 = station.soglia3,
+            "Non-monotonic thresholds for station {}",
+            station.nomestaz
+        );
+    }
+    expression_attribute_values.insert(
+        ":thresholds_valid".to_string(),
+        AttributeValue::Bool(thresholds_valid),
+    );
+
     let mut expression_attribute_names = std::collections::HashMap::new();
     expression_attribute_names.insert("#tsp".to_string(), "timestamp".to_string());
     expression_attribute_names.insert("#vl".to_string(), "value".to_string());
 
-    let update_expression = "SET #tsp = :new_timestamp, #vl = :new_value, idstazione = :idstazione, ordinamento = :ordinamento, lon = :lon, lat = :lat, soglia1 = :soglia1, soglia2 = :soglia2, soglia3 = :soglia3";
+    // The right-hand side of SET reads the stored item, so the replaced reading
+    // is kept as the previous one; a new item starts with previous == current
+    let update_expression = "SET previous_value = if_not_exists(#vl, :new_value), previous_timestamp = if_not_exists(#tsp, :new_timestamp), #tsp = :new_timestamp, #vl = :new_value, idstazione = :idstazione, ordinamento = :ordinamento, lon = :lon, lat = :lat, soglia1 = :soglia1, soglia2 = :soglia2, soglia3 = :soglia3, thresholds_valid = :thresholds_valid, unit = :unit";
 
     let condition_expression = "attribute_not_exists(#tsp) OR :new_timestamp > #tsp";
 
@@ -238,26 +402,130 @@ async fn put_station_into_dynamodb(
 
 async fn process_station(
     client: &reqwest::Client,
+    config: &ApiConfig,
     dynamodb_client: &DynamoDbClient,
     station: Station,
     table_name: &str,
 ) -> Result<(), BoxError> {
-    let station = fetch_station_data(client, station.clone())
-        .await
-        .map_err(|e| {
-            error!(
-                "Error fetching data for station {}: {:?}",
-                station.nomestaz, e
-            );
-            e
-        });
-    put_station_into_dynamodb(dynamodb_client, &station?, table_name).await?;
+    // The listing already carries the latest reading: skip the time series
+    let station = if station.value.is_some() && station.timestamp.is_some() {
+        station
+    } else {
+        fetch_station_data(client, config, station.clone())
+            .await
+            .map_err(|e| {
+                error!(
+                    "Error fetching data for station {}: {:?}",
+                    station.nomestaz, e
+                );
+                e
+            })?
+    };
+    put_station_into_dynamodb(dynamodb_client, &station, table_name).await?;
+
+    Ok(())
+}
+
+/// Status of the last run that could read the region listing, shown by the bot
+fn fetch_status_item(
+    region: &str,
+    timestamp: i64,
+    stations_updated: usize,
+) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("region".to_string(), AttributeValue::S(region.to_string())),
+        (
+            "last_fetch_success".to_string(),
+            AttributeValue::N(timestamp.to_string()),
+        ),
+        (
+            "stations_updated".to_string(),
+            AttributeValue::N(stations_updated.to_string()),
+        ),
+    ])
+}
 
+async fn put_fetch_status(
+    client: &DynamoDbClient,
+    table_name: &str,
+    item: HashMap<String, AttributeValue>,
+) -> Result<()> {
+    client
+        .put_item()
+        .table_name(table_name)
+        .set_item(Some(item))
+        .send()
+        .await?;
     Ok(())
 }
 
+fn parse_seed_item(item: Value) -> Result<Station, String> {
+    let station: Station =
+        serde_json::from_value(item).map_err(|e| format!("Invalid station record: {}", e))?;
+    if station.nomestaz.trim().is_empty() {
+        return Err("Missing 'nomestaz' in station record".to_string());
+    }
+    if station.idstazione.trim().is_empty() {
+        return Err(format!(
+            "Missing 'idstazione' for station {}",
+            station.nomestaz
+        ));
+    }
+    if station.timestamp.is_none() {
+        return Err(format!(
+            "Missing 'timestamp' for station {}",
+            station.nomestaz
+        ));
+    }
+    Ok(station)
+}
+
+async fn seed_stations(
+    dynamodb_client: &DynamoDbClient,
+    items: Vec<Value>,
+    table_name: &str,
+) -> SeedResult {
+    let total_records = items.len();
+    let mut seeded = 0;
+    for item in items {
+        let station = match parse_seed_item(item) {
+            Ok(station) => station,
+            Err(e) => {
+                error!(error = %e, "Rejected seed record");
+                continue;
+            }
+        };
+        match put_station_into_dynamodb(dynamodb_client, &station, table_name).await {
+            Ok(()) => seeded += 1,
+            Err(e) => error!(error = %e, "Error seeding station {}: {:?}", station.nomestaz, e),
+        }
+    }
+
+    info!(
+        stations_seeded = seeded,
+        total_records = total_records,
+        "Finished seeding stations"
+    );
+    SeedResult {
+        message: "Lambda executed successfully".to_string(),
+        stations_seeded: seeded,
+        stations_failed: total_records - seeded,
+        status_code: 200,
+    }
+}
+
 #[instrument]
-async fn lambda_handler(_: LambdaEvent<Value>) -> Result<Value, LambdaError> {
+async fn lambda_handler(event: LambdaEvent<Value>) -> Result<Value, LambdaError> {
+    if event.payload.get("action").is_some() {
+        let shared_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
+        let dynamodb_client = DynamoDbClient::new(&shared_config);
+        return match serde_json::from_value::<Action>(event.payload)? {
+            Action::Seed { items } => Ok(serde_json::to_value(
+                seed_stations(&dynamodb_client, items, "Stazioni").await,
+            )?),
+        };
+    }
+
     let http_client = reqwest::Client::builder()
         .timeout(Duration::from_secs(10))
         .build()?;
@@ -265,15 +533,21 @@ async fn lambda_handler(_: LambdaEvent<Value>) -> Result<Value, LambdaError> {
     let shared_config = aws_config::defaults(BehaviorVersion::latest()).load().await;
     let dynamodb_client = DynamoDbClient::new(&shared_config);
 
-    let latest_timestamp = fetch_latest_time(&http_client).await?;
-    let stations = fetch_stations(&http_client, latest_timestamp).await?;
+    let config = ApiConfig::from_env();
+    let latest_timestamp = fetch_latest_time(&http_client, &config).await?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as i64;
+    let stations = fetch_stations_with_fallback(
+        |timestamp| fetch_stations(&http_client, &config, timestamp),
+        latest_timestamp,
+        now,
+    )
+    .await?;
 
     let concurrency_limit = 50;
 
-    let process_futures = stations
-        .clone()
-        .into_iter()
-        .map(|station| process_station(&http_client, &dynamodb_client, station, "Stazioni"));
+    let process_futures = stations.clone().into_iter().map(|station| {
+        process_station(&http_client, &config, &dynamodb_client, station, "Stazioni")
+    });
 
     let process_results: Vec<_> = futures::stream::iter(process_futures)
         .buffer_unordered(concurrency_limit)
@@ -294,12 +568,21 @@ async fn lambda_handler(_: LambdaEvent<Value>) -> Result<Value, LambdaError> {
         total_stations = stations.len(),
         "Finished processing stations"
     );
-    Ok(json!({
-        "message": "Lambda executed successfully",
-        "stations_processed": stations.len(),
-        "stations_updated": successful_updates,
-        "statusCode": 200,
-    }))
+    if let Err(e) = put_fetch_status(
+        &dynamodb_client,
+        FETCH_STATUS_TABLE,
+        fetch_status_item(REGION_KEY, now, successful_updates),
+    )
+    .await
+    {
+        error!(error = %e, "Error storing fetch status");
+    }
+    Ok(serde_json::to_value(FetchResult {
+        message: "Lambda executed successfully".to_string(),
+        stations_processed: stations.len(),
+        stations_updated: successful_updates,
+        status_code: 200,
+    })?)
 }
 
 #[tokio::main]
@@ -317,3 +600,308 @@ async fn main() -> Result<(), LambdaError> {
     lambda_runtime::run(func).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn seed_record() -> Value {
+        json!({
+            "timestamp": 1729454542656_u64,
+            "idstazione": "-/1203460,4434222/simnbo",
+            "ordinamento": 1,
+            "nomestaz": "Cesena",
+            "lon": "1203460",
+            "lat": "4434222",
+            "soglia1": 1.0,
+            "soglia2": 2.0,
+            "soglia3": 3.0,
+            "value": 2.2
+        })
+    }
+
+    #[test]
+    fn parse_seed_action() {
+        let payload = json!({"action": "seed", "items": [seed_record()]});
+        let Action::Seed { items } = serde_json::from_value::<Action>(payload).unwrap();
+        assert_eq!(items.len(), 1);
+    }
+
+    #[test]
+    fn parse_seed_item_accepts_valid_record() {
+        let station = parse_seed_item(seed_record()).unwrap();
+        assert_eq!(station.nomestaz, "Cesena");
+        assert_eq!(station.timestamp, Some(1729454542656));
+    }
+
+    #[test]
+    fn parse_seed_item_rejects_missing_fields() {
+        let mut record = seed_record();
+        record.as_object_mut().unwrap().remove("soglia1");
+        assert!(parse_seed_item(record).is_err());
+    }
+
+    #[test]
+    fn parse_seed_item_rejects_missing_timestamp() {
+        let mut record = seed_record();
+        record["timestamp"] = Value::Null;
+        assert!(parse_seed_item(record).is_err());
+    }
+
+    #[test]
+    fn parse_seed_item_rejects_empty_name() {
+        let mut record = seed_record();
+        record["nomestaz"] = json!(" ");
+        assert!(parse_seed_item(record).is_err());
+    }
+
+    #[test]
+    fn thresholds_monotonic_accepts_increasing_thresholds() {
+        assert!(thresholds_monotonic([1.0, 2.0, 3.0]));
+        assert!(thresholds_monotonic([1.0, 1.0, 3.0]));
+    }
+
+    #[test]
+    fn thresholds_monotonic_rejects_swapped_thresholds() {
+        assert!(!thresholds_monotonic([2.0, 1.0, 3.0]));
+        assert!(!thresholds_monotonic([1.0, 3.0, 2.0]));
+    }
+
+    #[test]
+    fn thresholds_monotonic_ignores_missing_thresholds() {
+        assert!(thresholds_monotonic([0.0, 0.0, 0.0]));
+        assert!(thresholds_monotonic([1.5, 0.0, 3.0]));
+        assert!(!thresholds_monotonic([3.0, 0.0, 1.5]));
+    }
+
+    fn data_entry(value: &str, timestap: Option<u64>) -> Entry {
+        serde_json::from_value(json!({
+            "idstazione": "/id/",
+            "ordinamento": 1,
+            "nomestaz": "Cesena",
+            "lon": "12.24",
+            "lat": "44.13",
+            "soglia1": 1.0,
+            "soglia2": 2.0,
+            "soglia3": 3.0,
+            "value": value,
+            "timestap": timestap,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn station_from_entry_keeps_the_sensor_time() {
+        let station = station_from_entry(data_entry("2,20", Some(1729454542656))).unwrap();
+        assert_eq!(station.value, Some(2.2));
+        assert_eq!(station.timestamp, Some(1729454542656));
+    }
+
+    #[test]
+    fn station_from_entry_without_sensor_time_has_no_timestamp() {
+        let station = station_from_entry(data_entry("2,20", None)).unwrap();
+        assert_eq!(station.value, Some(2.2));
+        assert_eq!(station.timestamp, None);
+    }
+
+    #[test]
+    fn station_from_entry_without_value_has_no_timestamp() {
+        let station = station_from_entry(data_entry("", Some(1729454542656))).unwrap();
+        assert_eq!((station.value, station.timestamp), (None, None));
+    }
+
+    #[test]
+    fn parse_er_value_accepts_comma_decimal() {
+        assert_eq!(parse_er_value("2,20"), Some(2.2));
+    }
+
+    #[test]
+    fn parse_er_value_accepts_dot_decimal() {
+        assert_eq!(parse_er_value("2.20"), Some(2.2));
+    }
+
+    #[test]
+    fn parse_er_value_empty_is_none() {
+        assert_eq!(parse_er_value(""), None);
+        assert_eq!(parse_er_value("  "), None);
+    }
+
+    #[test]
+    fn parse_er_value_garbage_is_none() {
+        assert_eq!(parse_er_value("n/d"), None);
+        assert_eq!(parse_er_value("inf"), None);
+    }
+
+    #[test]
+    fn fetch_result_serializes_camel_case_keys() {
+        let result = FetchResult {
+            message: "Lambda executed successfully".to_string(),
+            stations_processed: 290,
+            stations_updated: 280,
+            status_code: 200,
+        };
+
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            json!({
+                "message": "Lambda executed successfully",
+                "stationsProcessed": 290,
+                "stationsUpdated": 280,
+                "statusCode": 200,
+            })
+        );
+    }
+
+    #[test]
+    fn seed_result_serializes_camel_case_keys() {
+        let result = SeedResult {
+            message: "Lambda executed successfully".to_string(),
+            stations_seeded: 3,
+            stations_failed: 1,
+            status_code: 200,
+        };
+
+        assert_eq!(
+            serde_json::to_value(result).unwrap(),
+            json!({
+                "message": "Lambda executed successfully",
+                "stationsSeeded": 3,
+                "stationsFailed": 1,
+                "statusCode": 200,
+            })
+        );
+    }
+
+    #[test]
+    fn station_values_keep_f64_precision() {
+        let mut record = seed_record();
+        record["value"] = json!(2.345);
+        record["soglia1"] = json!(2.345);
+        let station = parse_seed_item(record).unwrap();
+        assert_eq!(station.value.unwrap().to_string(), "2.345");
+        assert_eq!(station.soglia1.to_string(), "2.345");
+
+        let data: StationData =
+            serde_json::from_str(r#"{"t": 1729454542656, "v": 2.345}"#).unwrap();
+        assert_eq!(data.v.unwrap().to_string(), "2.345");
+    }
+
+    #[test]
+    fn parse_seed_item_defaults_unit() {
+        assert_eq!(parse_seed_item(seed_record()).unwrap().unit, "m");
+    }
+
+    #[test]
+    fn parse_seed_item_keeps_unit() {
+        let mut record = seed_record();
+        record["unit"] = json!("m³/s");
+        assert_eq!(parse_seed_item(record).unwrap().unit, "m³/s");
+    }
+
+    #[test]
+    fn api_config_defaults_and_overrides() {
+        assert_eq!(
+            ApiConfig::from_values(None, None, Some("not a number".to_string())),
+            ApiConfig {
+                api_base: API_BASE.to_string(),
+                sensor_variable: SENSOR_VARIABLE.to_string(),
+                latest_time_seed: LATEST_TIME_SEED,
+            }
+        );
+        assert_eq!(
+            ApiConfig::from_values(
+                Some("https://example.org/api".to_string()),
+                Some("1,2,3".to_string()),
+                Some(" 42 ".to_string())
+            ),
+            ApiConfig {
+                api_base: "https://example.org/api".to_string(),
+                sensor_variable: "1,2,3".to_string(),
+                latest_time_seed: 42,
+            }
+        );
+    }
+
+    fn station_named(nomestaz: &str) -> Station {
+        let mut record = seed_record();
+        record["nomestaz"] = json!(nomestaz);
+        parse_seed_item(record).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_stations_falls_back_to_current_time_on_empty_listing() {
+        let requested = std::sync::Mutex::new(Vec::new());
+        let stations = fetch_stations_with_fallback(
+            |timestamp| {
+                requested.lock().unwrap().push(timestamp);
+                async move {
+                    Ok(if timestamp == 2000 {
+                        vec![station_named("Cesena")]
+                    } else {
+                        vec![]
+                    })
+                }
+            },
+            1000,
+            2000,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stations.len(), 1);
+        assert_eq!(*requested.lock().unwrap(), vec![1000, 2000]);
+    }
+
+    #[tokio::test]
+    async fn fetch_stations_without_fallback_when_listing_is_populated() {
+        let requested = std::sync::Mutex::new(Vec::new());
+        let stations = fetch_stations_with_fallback(
+            |timestamp| {
+                requested.lock().unwrap().push(timestamp);
+                async { Ok(vec![station_named("Cesena")]) }
+            },
+            1000,
+            2000,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stations.len(), 1);
+        assert_eq!(*requested.lock().unwrap(), vec![1000]);
+    }
+
+    #[tokio::test]
+    async fn fetch_stations_fails_when_both_listings_are_empty() {
+        let requested = std::sync::Mutex::new(Vec::new());
+        let result = fetch_stations_with_fallback(
+            |timestamp| {
+                requested.lock().unwrap().push(timestamp);
+                async { Ok(vec![]) }
+            },
+            1000,
+            2000,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(*requested.lock().unwrap(), vec![1000, 2000]);
+    }
+
+    #[test]
+    fn fetch_status_item_keys_by_region() {
+        let item = fetch_status_item(REGION_KEY, 1729454542656, 290);
+        assert_eq!(
+            item.get("region"),
+            Some(&AttributeValue::S("emilia_romagna".to_string()))
+        );
+        assert_eq!(
+            item.get("last_fetch_success"),
+            Some(&AttributeValue::N("1729454542656".to_string()))
+        );
+        assert_eq!(
+            item.get("stations_updated"),
+            Some(&AttributeValue::N("290".to_string()))
+        );
+    }
+}
@@ -51,6 +51,19 @@ chats_table = dynamodb.Table(
     ),
 )
 
+fetch_status_table = dynamodb.Table(
+    f"{RESOURCES_PREFIX}-fetch-status",
+    name="FetchStatus",
+    billing_mode="PAY_PER_REQUEST",
+    hash_key="region",
+    attributes=[
+        dynamodb.TableAttributeArgs(
+            name="region",
+            type="S",
+        ),
+    ],
+)
+
 fetcher_role = iam.Role(
     f"{RESOURCES_PREFIX}-fetcher",
     name=f"{RESOURCES_PREFIX}-fetcher",
@@ -85,7 +98,14 @@ fetcher_role = iam.Role(
                             "dynamodb:GetItem",
                         ],
                         "Resources": [stazioni_table.arn],
-                    }
+                    },
+                    {
+                        "Effect": "Allow",
+                        "Actions": [
+                            "dynamodb:PutItem",
+                        ],
+                        "Resources": [fetch_status_table.arn],
+                    },
                 ],
             ).json,
         )
@@ -121,8 +141,10 @@ bot_role = iam.Role(
                         "Effect": "Allow",
                         "Actions": [
                             "dynamodb:Query",
+                            "dynamodb:Scan",
                             "dynamodb:UpdateItem",
                             "dynamodb:GetItem",
+                            "dynamodb:DescribeTable",
                         ],
                         "Resources": [stazioni_table.arn, chats_table.arn],
                     },
@@ -133,12 +155,37 @@ bot_role = iam.Role(
                         ],
                         "Resources": [chats_table.arn],
                     },
+                    {
+                        "Effect": "Allow",
+                        "Actions": [
+                            "dynamodb:DeleteItem",
+                        ],
+                        "Resources": [stazioni_table.arn],
+                    },
+                    {
+                        "Effect": "Allow",
+                        "Actions": [
+                            "dynamodb:GetItem",
+                        ],
+                        "Resources": [fetch_status_table.arn],
+                    },
                 ],
             ).json,
         )
     ],
 )
 
+# Portal overrides read by both the fetcher and the bot, so that they always
+# agree on the portal contract; unset values keep the defaults in the code
+ER_PORTAL_VARIABLES = {
+    name: value
+    for name, value in {
+        "ER_API_BASE": pulumi.Config().get("er-api-base"),
+        "ER_SENSOR_VARIABLE": pulumi.Config().get("er-sensor-variable"),
+    }.items()
+    if value
+}
+
 fetcher_lambda = lambda_.Function(
     f"{RESOURCES_PREFIX}-fetcher",
     code=pulumi.FileArchive("./dummy.zip"),
@@ -150,6 +197,7 @@ fetcher_lambda = lambda_.Function(
         "variables": {
             "ENVIRONMENT": pulumi.get_stack(),
             "RUST_LOG": "info",
+            **ER_PORTAL_VARIABLES,
         },
     },
     memory_size=512,
@@ -168,6 +216,7 @@ bot_lambda = lambda_.Function(
             "RUST_LOG": "info",
             "ENVIRONMENT": pulumi.get_stack(),
             "TELOXIDE_TOKEN": pulumi.Config().require_secret("telegram-bot-token"),
+            **ER_PORTAL_VARIABLES,
         },
     },
     memory_size=128,
@@ -314,6 +363,7 @@ if pulumi.get_stack() == "production":
         authorization_token=telegram_authorization_token,
         react_on=[
             "message",
+            "channel_post",
             "inline_query",
         ],
         url=f"https://{CUSTOM_DOMAIN_NAME}/erfiume_bot",